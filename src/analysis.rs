@@ -0,0 +1,207 @@
+use crate::diagnostic::Diagnostic;
+use crate::error::Error;
+use crate::lexer::{BFToken, Span, Token};
+
+/// Lowest and highest cells the pointer can reach, relative to the first
+/// cell, with the tokens that first reach them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PointerRange {
+    pub min: i64,
+    pub max: i64,
+    pub min_span: Span,
+    pub max_span: Span,
+}
+
+struct Walker<'a> {
+    tokens: &'a [Token],
+    /// Index of the matching bracket for every `[` and `]`
+    matching: Vec<usize>,
+    range: PointerRange,
+}
+
+impl Walker<'_> {
+    fn visit(&mut self, offset: i64, span: Span) {
+        if offset < self.range.min {
+            self.range.min = offset;
+            self.range.min_span = span;
+        }
+        if offset > self.range.max {
+            self.range.max = offset;
+            self.range.max_span = span;
+        }
+    }
+
+    // Walks tokens[start..end] starting at `offset` and returns the offset at
+    // the end, or None as soon as a loop body does not return the pointer to
+    // where it started. `fresh` means no cell has been written yet, so the
+    // whole tape is zero.
+    fn block(&mut self, start: usize, end: usize, mut offset: i64, mut fresh: bool) -> Option<i64> {
+        let mut zero = fresh;
+        let mut i = start;
+        while i < end {
+            let token = self.tokens[i];
+            match token.kind {
+                BFToken::IncrementPointer(n) => {
+                    offset += n as i64;
+                    self.visit(offset, token.span);
+                    zero = fresh;
+                }
+                BFToken::DecrementPointer(n) => {
+                    offset -= n as i64;
+                    self.visit(offset, token.span);
+                    zero = fresh;
+                }
+                BFToken::IncrementValue(_)
+                | BFToken::DecrementValue(_)
+                | BFToken::InputValue(_) => {
+                    fresh = false;
+                    zero = false;
+                }
+                BFToken::OutputValue(_) | BFToken::DebugDump => {}
+                BFToken::LoopStart => {
+                    let close = self.matching[i];
+                    // A loop on a cell known to be zero is never entered
+                    if !zero {
+                        // Entering needs a nonzero cell, so something was written
+                        if self.block(i + 1, close, offset, false)? != offset {
+                            return None;
+                        }
+                        fresh = false;
+                    }
+                    zero = true;
+                    i = close;
+                }
+                BFToken::LoopEnd => unreachable!("brackets are matched by the caller"),
+            }
+            i += 1;
+        }
+        Some(offset)
+    }
+}
+
+/// Computes the range of cells the pointer can reach. Returns None when that
+/// is undecidable, i.e. some loop moves the pointer by a nonzero amount per
+/// iteration. Loops that provably never run are skipped. `tokens` must have
+/// balanced brackets.
+pub fn pointer_range(tokens: &[Token]) -> Option<PointerRange> {
+    let mut matching = vec![0; tokens.len()];
+    let mut stack = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            BFToken::LoopStart => stack.push(i),
+            BFToken::LoopEnd => {
+                let open = stack.pop().expect("brackets must be balanced");
+                matching[open] = i;
+                matching[i] = open;
+            }
+            _ => {}
+        }
+    }
+    let mut walker = Walker {
+        tokens,
+        matching,
+        range: PointerRange {
+            min: 0,
+            max: 0,
+            min_span: Span::default(),
+            max_span: Span::default(),
+        },
+    };
+    walker.block(0, tokens.len(), 0, true)?;
+    Some(walker.range)
+}
+
+/// Errors if the pointer can provably leave the tape. Pass None as
+/// `tape_size` for a tape without a right end.
+pub fn check_pointer_range(
+    tokens: &[Token],
+    tape_size: Option<usize>,
+) -> Result<Option<PointerRange>, Error> {
+    let range = match pointer_range(tokens) {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+    let mut diagnostics = Vec::new();
+    if range.min < 0 {
+        diagnostics.push(
+            Diagnostic::error("pointer can move left of the first cell", range.min_span)
+                .with_label(format!("reaches cell {} here", range.min)),
+        );
+    }
+    if let Some(size) = tape_size
+        && range.max >= size as i64
+    {
+        diagnostics.push(
+            Diagnostic::error("pointer can move past the end of the tape", range.max_span)
+                .with_label(format!(
+                    "reaches cell {} here, but the tape has {} cells",
+                    range.max, size
+                ))
+                .with_note(format!("use --tape-size {} or larger", range.max + 1), None),
+        );
+    }
+    if diagnostics.is_empty() {
+        Ok(Some(range))
+    } else {
+        Err(Error::Syntax(diagnostics))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{BFLexer, Dialect};
+
+    fn range(input: &str) -> Option<(i64, i64)> {
+        let tokens = BFLexer::new(input.chars())
+            .with_dialect(Dialect::Extended)
+            .tokenize()
+            .unwrap();
+        pointer_range(&tokens).map(|r| (r.min, r.max))
+    }
+
+    #[test]
+    fn test_straight_line() {
+        assert_eq!(range(">>+<.>>>"), Some((0, 4)));
+        assert_eq!(range("+<"), Some((-1, 0)));
+    }
+
+    #[test]
+    fn test_balanced_loops() {
+        assert_eq!(range("+[->>+<<[>>>-<<<-]]"), Some((0, 3)));
+        assert_eq!(range("+[<+>-]"), Some((-1, 0)));
+    }
+
+    #[test]
+    fn test_unbalanced_loop() {
+        assert_eq!(range("+[>+]"), None);
+        assert_eq!(range("+[[-]<]"), None);
+    }
+
+    #[test]
+    fn test_dead_loops_skipped() {
+        // comment loop at the start of the program
+        assert_eq!(range("[ < or > ] >+"), Some((0, 1)));
+        // loop straight after another one
+        assert_eq!(range("+[-][<<<]"), Some((0, 0)));
+        // the cell moved onto is still zero before anything is written
+        assert_eq!(range(">[<<]"), Some((0, 1)));
+        assert_eq!(range("+>[<<+>>-]"), Some((-1, 1)));
+    }
+
+    #[test]
+    fn test_check_pointer_range() {
+        let tokens = BFLexer::new("+\n>>>".chars()).tokenize().unwrap();
+        assert!(check_pointer_range(&tokens, Some(4)).is_ok());
+        assert!(check_pointer_range(&tokens, None).is_ok());
+        match check_pointer_range(&tokens, Some(3)) {
+            Err(Error::Syntax(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!(diagnostics[0].span.line, 2);
+            }
+            other => panic!("expected range error, got {:?}", other),
+        }
+        let tokens = BFLexer::new("+[<]".chars()).tokenize().unwrap();
+        assert!(matches!(check_pointer_range(&tokens, Some(1)), Ok(None)));
+    }
+}
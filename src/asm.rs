@@ -1,14 +1,58 @@
+/// Target architectures the assembler can generate code for.
+pub const TARGETS: &[&str] = &["unix", "win64"];
+
+/// Exit status of a program that moved the tape pointer out of bounds.
+pub const BOUNDS_EXIT_CODE: u8 = 3;
+
+/// Pending bounds check stubs after which a streaming compile emits them
+/// inline, so memory use doesn't grow with the length of the program.
+pub const BOUNDS_STUB_CHUNK: usize = 256;
+
+/// Size of the guard pages placed around the tape with `--guard-pages`.
+pub const PAGE_SIZE: usize = 4096;
+
+const BOUNDS_MSG: &str = "Error: tape pointer out of bounds at line ";
+const BOUNDS_COLUMN_MSG: &str = ", column ";
+const SEGV_LEFT_MSG: &str = "Error: tape pointer out of range (left)";
+const SEGV_RIGHT_MSG: &str = "Error: tape pointer out of range (right)";
+const GROW_FAILED_MSG: &str = "Error: out of memory while growing the tape";
+const MAP_FAILED_MSG: &str = "Error: could not map the tape";
+const DEBUG_POINTER_MSG: &str = "# pointer ";
+const DEBUG_CELLS_MSG: &str = ", cells ";
+
+/// Number of cells shown around the pointer by `#`. The window starts at a
+/// multiple of this.
+pub const DEBUG_WINDOW: usize = 16;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Assembler {
     pub target_arch: String,
     pub asm: String,
     pub pretty: bool,
     pub tape_size: usize,
+    /// Source line and column of the bounds checks whose failure stubs
+    /// haven't been emitted yet
+    pub bounds_stubs: Vec<(usize, usize)>,
+    /// Number of bounds checks emitted so far, used for unique labels
+    pub bounds_checks: usize,
+    pub guard_pages: bool,
+    pub grow_tape: bool,
+    pub wrap_pointer: bool,
+    /// Use the current cell as the process exit status
+    pub exit_cell: bool,
+    /// Number of growth checks emitted so far, used for unique labels
+    pub grow_checks: u32,
+    /// Whether any `#` was emitted, so the dump routine is needed
+    pub debug_dumps: bool,
+    /// Input baked into the data section, read by `,` instead of stdin
+    pub input: Option<Vec<u8>>,
+    /// `,` calls the input routine although the input isn't known yet
+    pub deferred_input: bool,
 }
 
 impl Assembler {
     pub fn new(target_arch: &str, pretty: bool, tape_size: usize) -> Self {
-        if !["unix", "win64"].contains(&target_arch) {
+        if !TARGETS.contains(&target_arch) {
             panic!("Unsupported target architecture: {}", target_arch);
         }
         Assembler {
@@ -16,9 +60,62 @@ impl Assembler {
             asm: String::new(),
             pretty,
             tape_size,
+            bounds_stubs: Vec::new(),
+            bounds_checks: 0,
+            guard_pages: false,
+            grow_tape: false,
+            wrap_pointer: false,
+            exit_cell: false,
+            grow_checks: 0,
+            debug_dumps: false,
+            input: None,
+            deferred_input: false,
         }
     }
 
+    /// Map the tape between two `PROT_NONE` pages and report faults on them.
+    /// The tape is rounded up to whole pages. Only supported on unix.
+    pub fn with_guard_pages(mut self, guard_pages: bool) -> Self {
+        self.guard_pages = guard_pages;
+        self
+    }
+
+    /// Heap-allocate the tape with `tape_size` bytes and double it whenever
+    /// the pointer moves past the right end. New cells start at zero. Not
+    /// combinable with guard pages or a wrapping pointer.
+    pub fn with_grow_tape(mut self, grow_tape: bool) -> Self {
+        self.grow_tape = grow_tape;
+        self
+    }
+
+    /// Exit with the value of the current cell instead of 0.
+    pub fn with_exit_cell(mut self, exit_cell: bool) -> Self {
+        self.exit_cell = exit_cell;
+        self
+    }
+
+    /// Read `,` from `input` embedded in the executable. Reads past its end
+    /// leave the cell unchanged, like EOF on stdin.
+    pub fn with_input(mut self, input: Option<Vec<u8>>) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Read `,` through the input routine even though the embedded input is
+    /// only known once the code has been emitted, as when streaming. Pass it
+    /// with `with_input` before `footer`; without any, the routine reads
+    /// stdin.
+    pub fn with_deferred_input(mut self, deferred_input: bool) -> Self {
+        self.deferred_input = deferred_input;
+        self
+    }
+
+    /// Make `>` and `<` wrap around the ends of the tape.
+    pub fn with_wrap_pointer(mut self, wrap_pointer: bool) -> Self {
+        self.wrap_pointer = wrap_pointer;
+        self
+    }
+
     // Builder functions
     pub fn header(mut self) -> Self {
         match self.target_arch.as_str() {
@@ -31,11 +128,16 @@ impl Assembler {
                         .push_str(format!(";\n; Tape size: {}b\n\n", self.tape_size).as_str());
                 }
                 self.asm.push_str("section .bss\n");
-                self.asm
-                    .push_str(&format!("{indent}tape resb {}\n\n", self.tape_size));
+                self = self.tape_storage();
                 self.asm.push_str("section .text\n");
                 self.asm.push_str("global _start\n\n");
                 self.asm.push_str("_start:\n");
+                if self.guard_pages {
+                    return self.map_guarded_tape();
+                }
+                if self.grow_tape {
+                    return self.allocate_grow_tape();
+                }
                 self.asm.push_str(&format!("{indent}mov rsi, tape\n"));
 
                 self
@@ -50,15 +152,22 @@ impl Assembler {
                 }
                 self.asm.push_str("section .data\n");
                 self.asm.push_str("    fmt_char db '%c', 0\n\n");
-                self.asm.push_str("section .bss\n");
-                self.asm
-                    .push_str(&format!("{indent}tape resb {}\n\n", self.tape_size));
+                self = self.tape_storage();
                 self.asm.push_str("section .text\n");
                 self.asm.push_str("extern printf\n");
                 self.asm.push_str("extern scanf\n");
+                self.asm.push_str("extern calloc\n");
+                self.asm.push_str("extern realloc\n");
+                self.asm.push_str("extern memset\n");
+                self.asm.push_str("extern GetStdHandle\n");
+                self.asm.push_str("extern WriteFile\n");
+                self.asm.push_str("extern ExitProcess\n");
                 self.asm.push_str("global main\n\n");
                 self.asm.push_str("main:\n");
                 self.asm.push_str(&format!("{indent}sub rsp, 40\n")); // Win64 ABI shadow space
+                if self.grow_tape {
+                    return self.allocate_grow_tape();
+                }
                 self.asm.push_str(&format!("{indent}mov rsi, tape\n"));
                 self
             }
@@ -78,28 +187,147 @@ impl Assembler {
                     "{indent}mov rax, 60{}\n",
                     if self.pretty { "       ; sys_exit" } else { "" }
                 ));
-                self.asm.push_str(&format!(
-                    "{indent}xor rdi, rdi{}\n",
-                    if self.pretty { "       ; status 0" } else { "" }
-                ));
+                if self.exit_cell {
+                    self.asm.push_str(&format!(
+                        "{indent}movzx rdi, byte [rsi]{}\n",
+                        if self.pretty { " ; status = cell" } else { "" }
+                    ));
+                } else {
+                    self.asm.push_str(&format!(
+                        "{indent}xor rdi, rdi{}\n",
+                        if self.pretty { "       ; status 0" } else { "" }
+                    ));
+                }
                 self.asm.push_str(&format!("{indent}syscall\n"));
-                self
+                self.runtime()
             }
             "win64" => {
                 let indent = if self.pretty { "    " } else { "" };
                 if self.pretty {
                     self.asm.push_str(&format!("\n{indent}; Exit program\n"));
                 }
+                if self.exit_cell {
+                    self.asm
+                        .push_str(&format!("{indent}movzx eax, byte [rsi]\n"));
+                } else {
+                    self.asm.push_str(&format!("{indent}xor eax, eax\n"));
+                }
                 self.asm.push_str(&format!("{indent}add rsp, 40\n")); // restore stack
                 self.asm.push_str(&format!("{indent}ret\n"));
-                self
+                self.runtime()
             }
             _ => unreachable!("Unsupported target architecture"),
         }
     }
 
+    fn tape_storage(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        self.asm.push_str("section .bss\n");
+        if self.grow_tape {
+            self.asm.push_str(&format!("{indent}tape_base resq 1\n"));
+            self.asm.push_str(&format!("{indent}tape_end resq 1\n\n"));
+        } else if self.guard_pages {
+            self.asm.push_str(&format!("{indent}tape_base resq 1\n\n"));
+        } else {
+            self.asm
+                .push_str(&format!("{indent}tape resb {}\n\n", self.tape_size));
+        }
+        self
+    }
+
+    fn allocate_grow_tape(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        // mremap works on whole pages, so keep the size page aligned
+        let tape_bytes = self.tape_size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+        if self.pretty {
+            self.asm
+                .push_str(&format!("{indent}; Allocate growable tape\n"));
+        }
+        let lines: Vec<String> = match self.target_arch.as_str() {
+            "unix" => vec![
+                "mov rax, 9".to_string(),
+                "xor rdi, rdi".to_string(),
+                format!("mov rsi, {}", tape_bytes),
+                "mov rdx, 3".to_string(),
+                "mov r10, 0x22".to_string(),
+                "mov r8, -1".to_string(),
+                "xor r9, r9".to_string(),
+                "syscall".to_string(),
+                "cmp rax, -4095".to_string(),
+                "jae rt_grow_failed".to_string(),
+            ],
+            "win64" => vec![
+                format!("mov rcx, {}", tape_bytes),
+                "mov rdx, 1".to_string(),
+                "call calloc".to_string(),
+                "test rax, rax".to_string(),
+                "jz rt_grow_failed".to_string(),
+            ],
+            _ => unreachable!("Unsupported target architecture"),
+        };
+        for line in lines {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm
+            .push_str(&format!("{indent}mov [tape_base], rax\n"));
+        self.asm.push_str(&format!("{indent}mov rsi, rax\n"));
+        self.asm
+            .push_str(&format!("{indent}add rax, {}\n", tape_bytes));
+        self.asm.push_str(&format!("{indent}mov [tape_end], rax\n"));
+        self
+    }
+
+    // Cell 0 directly follows the left guard page, so any step off the left
+    // end faults. The rounding slack extends the tape on the right.
+    fn map_guarded_tape(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        let tape_bytes = self.tape_size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+        let comment = |text: &'static str| if self.pretty { text } else { "" };
+        if self.pretty {
+            self.asm
+                .push_str(&format!("{indent}; Map tape with guard pages\n"));
+        }
+        let lines = [
+            format!("mov rax, 9{}", comment("        ; sys_mmap")),
+            "xor rdi, rdi".to_string(),
+            format!("mov rsi, {}", tape_bytes + 2 * PAGE_SIZE),
+            format!("xor rdx, rdx{}", comment("      ; PROT_NONE")),
+            format!(
+                "mov r10, 0x22{}",
+                comment("     ; MAP_PRIVATE | MAP_ANONYMOUS")
+            ),
+            "mov r8, -1".to_string(),
+            "xor r9, r9".to_string(),
+            "syscall".to_string(),
+            format!("test rax, rax{}", comment("      ; -errno on failure")),
+            "js rt_map_failed".to_string(),
+            format!("lea rdi, [rax + {}]", PAGE_SIZE),
+            "mov [tape_base], rdi".to_string(),
+            format!("mov rsi, {}", tape_bytes),
+            format!("mov rdx, 3{}", comment("        ; PROT_READ | PROT_WRITE")),
+            format!("mov rax, 10{}", comment("       ; sys_mprotect")),
+            "syscall".to_string(),
+            "test rax, rax".to_string(),
+            "js rt_map_failed".to_string(),
+            format!("mov rax, 13{}", comment("       ; sys_rt_sigaction")),
+            format!("mov rdi, 11{}", comment("       ; SIGSEGV")),
+            "mov rsi, segv_action".to_string(),
+            "xor rdx, rdx".to_string(),
+            "mov r10, 8".to_string(),
+            "syscall".to_string(),
+            "mov rsi, [tape_base]".to_string(),
+        ];
+        for line in lines {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self
+    }
+
     // commands
     pub fn inc_pointer(mut self, n: u32) -> Self {
+        if self.wrap_pointer {
+            return self.wrapped_move(n, true);
+        }
         match self.target_arch.as_str() {
             "unix" => {
                 let indent = if self.pretty { "    " } else { "" };
@@ -108,7 +336,7 @@ impl Assembler {
                     n,
                     if self.pretty { "         ; >" } else { "" }
                 ));
-                self
+                self.grow_check()
             }
             "win64" => {
                 let indent = if self.pretty { "    " } else { "" };
@@ -117,13 +345,74 @@ impl Assembler {
                     n,
                     if self.pretty { "         ; >" } else { "" }
                 ));
-                self
+                self.grow_check()
             }
             _ => unreachable!("Unsupported target architecture"),
         }
     }
 
+    // Moves the pointer modulo the tape size. Works on the offset from the
+    // tape start so the same code serves static and mmap'd tapes.
+    fn wrapped_move(mut self, n: u32, forward: bool) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        let size = self.tape_size;
+        let n = n as usize % size;
+        if n == 0 {
+            return self;
+        }
+        let (op, symbol) = if forward { ("add", ">") } else { ("sub", "<") };
+        if self.guard_pages {
+            self.asm
+                .push_str(&format!("{indent}mov rax, [tape_base]\n"));
+        } else {
+            self.asm.push_str(&format!("{indent}mov rax, tape\n"));
+        }
+        self.asm.push_str(&format!("{indent}sub rsi, rax\n"));
+        self.asm.push_str(&format!(
+            "{indent}{op} rsi, {}{}\n",
+            n,
+            if self.pretty {
+                format!("         ; {symbol} (wrapping)")
+            } else {
+                String::new()
+            }
+        ));
+        if size.is_power_of_two() {
+            self.asm
+                .push_str(&format!("{indent}and rsi, {}\n", size - 1));
+        } else if forward {
+            self.asm
+                .push_str(&format!("{indent}lea rdx, [rsi - {}]\n", size));
+            self.asm.push_str(&format!("{indent}cmp rsi, {}\n", size));
+            self.asm.push_str(&format!("{indent}cmovae rsi, rdx\n"));
+        } else {
+            // lea leaves the borrow from the sub above intact
+            self.asm
+                .push_str(&format!("{indent}lea rdx, [rsi + {}]\n", size));
+            self.asm.push_str(&format!("{indent}cmovb rsi, rdx\n"));
+        }
+        self.asm.push_str(&format!("{indent}add rsi, rax\n"));
+        self
+    }
+
+    fn grow_check(mut self) -> Self {
+        if !self.grow_tape {
+            return self;
+        }
+        let indent = if self.pretty { "    " } else { "" };
+        let id = self.grow_checks;
+        self.grow_checks += 1;
+        self.asm.push_str(&format!("{indent}cmp rsi, [tape_end]\n"));
+        self.asm.push_str(&format!("{indent}jb grow_skip_{}\n", id));
+        self.asm.push_str(&format!("{indent}call rt_grow_tape\n"));
+        self.asm.push_str(&format!("grow_skip_{}:\n", id));
+        self
+    }
+
     pub fn dec_pointer(mut self, n: u32) -> Self {
+        if self.wrap_pointer {
+            return self.wrapped_move(n, false);
+        }
         match self.target_arch.as_str() {
             "unix" => {
                 let indent = if self.pretty { "    " } else { "" };
@@ -244,6 +533,17 @@ impl Assembler {
     }
 
     pub fn input_value(mut self, n: u32) -> Self {
+        if self.input.is_some() || self.deferred_input {
+            let indent = if self.pretty { "    " } else { "" };
+            for _ in 0..n {
+                if self.pretty {
+                    self.asm
+                        .push_str(&format!("{indent}; Input to current cell\n"));
+                }
+                self.asm.push_str(&format!("{indent}call rt_read_input\n"));
+            }
+            return self;
+        }
         match self.target_arch.as_str() {
             "unix" => {
                 let indent = if self.pretty { "    " } else { "" };
@@ -351,9 +651,626 @@ impl Assembler {
         }
     }
 
+    /// Traps with `BOUNDS_EXIT_CODE` if the pointer is outside the tape.
+    /// `line` and `column` are reported in the error message.
+    pub fn check_bounds(mut self, line: usize, column: usize) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        let stub = self.bounds_checks;
+        self.bounds_checks += 1;
+        if self.pretty {
+            self.asm.push_str(&format!("{indent}; Bounds check\n"));
+        }
+        if self.grow_tape {
+            // The right end moves with the tape, only the left one can fail
+            self.asm
+                .push_str(&format!("{indent}cmp rsi, [tape_base]\n"));
+            self.asm
+                .push_str(&format!("{indent}jb bounds_fail_{}\n", stub));
+            self.bounds_stubs.push((line, column));
+            return self;
+        }
+        // Offsets below the tape wrap around to huge values, so a single
+        // unsigned compare catches both ends.
+        self.asm.push_str(&format!("{indent}mov rax, rsi\n"));
+        if self.guard_pages {
+            self.asm
+                .push_str(&format!("{indent}mov rdx, [tape_base]\n"));
+        } else {
+            self.asm.push_str(&format!("{indent}mov rdx, tape\n"));
+        }
+        self.asm.push_str(&format!("{indent}sub rax, rdx\n"));
+        self.asm
+            .push_str(&format!("{indent}cmp rax, {}\n", self.tape_size));
+        self.asm
+            .push_str(&format!("{indent}jae bounds_fail_{}\n", stub));
+        self.bounds_stubs.push((line, column));
+        self
+    }
+
+    /// Prints the pointer position and the cells around it to stderr.
+    pub fn debug_dump(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        if self.pretty {
+            self.asm.push_str(&format!("{indent}; Debug dump\n"));
+        }
+        self.asm.push_str(&format!("{indent}call rt_debug_dump\n"));
+        self.debug_dumps = true;
+        self
+    }
+
     pub fn build(self) -> String {
         self.asm
     }
+
+    // Out-of-line error paths and the helper routines they need. Emitted
+    // after the exit code so the hot path stays straight-line.
+    fn runtime(mut self) -> Self {
+        let bounds = self.bounds_checks > 0;
+        let debug = self.debug_dumps;
+        let input = self.input.take();
+        let stdin_input = input.is_none() && self.deferred_input;
+        if !bounds
+            && !debug
+            && input.is_none()
+            && !stdin_input
+            && !self.guard_pages
+            && !self.grow_tape
+        {
+            self.input = input;
+            return self;
+        }
+        let indent = if self.pretty { "    " } else { "" };
+        if bounds {
+            self = self.bounds_error_routine();
+        }
+        if self.guard_pages {
+            self = self.segv_handler_routine();
+        }
+        if self.grow_tape {
+            self = self.grow_tape_routine();
+        }
+        if debug {
+            self = self.debug_dump_routine();
+        }
+        if input.is_some() {
+            self = self.read_input_routine();
+        } else if stdin_input {
+            self = self.read_stdin_routine();
+        }
+        if bounds || debug {
+            self = self.write_decimal_routine();
+        }
+        self = self.write_stderr_routine();
+
+        self.asm.push_str("\nsection .data\n");
+        if bounds {
+            self.asm
+                .push_str(&format!("{indent}bounds_msg db '{}'\n", BOUNDS_MSG));
+            self.asm.push_str(&format!(
+                "{indent}bounds_column_msg db '{}'\n",
+                BOUNDS_COLUMN_MSG
+            ));
+        }
+        if debug {
+            self.asm.push_str(&format!(
+                "{indent}debug_pointer_msg db '{}'\n",
+                DEBUG_POINTER_MSG
+            ));
+            self.asm.push_str(&format!(
+                "{indent}debug_cells_msg db '{}'\n",
+                DEBUG_CELLS_MSG
+            ));
+            // One char each, written with rt_debug_char
+            self.asm.push_str(&format!("{indent}debug_dash db '-'\n"));
+            self.asm.push_str(&format!("{indent}debug_colon db ':'\n"));
+            self.asm.push_str(&format!("{indent}debug_space db ' '\n"));
+            self.asm.push_str(&format!("{indent}debug_open db '['\n"));
+            self.asm.push_str(&format!("{indent}debug_close db ']'\n"));
+        }
+        if bounds || debug {
+            self.asm.push_str(&format!("{indent}newline db 10\n"));
+        }
+        if let Some(input) = &input {
+            self.asm
+                .push_str(&format!("{indent}input_len equ {}\n", input.len()));
+            if input.is_empty() {
+                self.asm.push_str("input_data:\n");
+            }
+            for (i, chunk) in input.chunks(16).enumerate() {
+                let bytes: Vec<String> = chunk.iter().map(|b| b.to_string()).collect();
+                self.asm.push_str(&format!(
+                    "{indent}{}db {}\n",
+                    if i == 0 { "input_data " } else { "" },
+                    bytes.join(", ")
+                ));
+            }
+        }
+        if self.guard_pages {
+            self.asm.push_str(&format!(
+                "{indent}segv_left_msg db '{}', 10\n",
+                SEGV_LEFT_MSG
+            ));
+            self.asm.push_str(&format!(
+                "{indent}segv_right_msg db '{}', 10\n",
+                SEGV_RIGHT_MSG
+            ));
+            self.asm.push_str(&format!(
+                "{indent}map_failed_msg db '{}', 10\n",
+                MAP_FAILED_MSG
+            ));
+            // struct kernel_sigaction; x86-64 requires SA_RESTORER
+            self.asm
+                .push_str(&format!("{indent}segv_action dq segv_handler\n"));
+            self.asm.push_str(&format!(
+                "{indent}dq 0x04000004{}\n",
+                if self.pretty {
+                    "  ; SA_SIGINFO | SA_RESTORER"
+                } else {
+                    ""
+                }
+            ));
+            self.asm.push_str(&format!("{indent}dq segv_restorer\n"));
+            self.asm.push_str(&format!("{indent}dq 0\n"));
+        }
+        if self.grow_tape {
+            self.asm.push_str(&format!(
+                "{indent}grow_failed_msg db '{}', 10\n",
+                GROW_FAILED_MSG
+            ));
+        }
+        self.asm.push_str("\nsection .bss\n");
+        if bounds || debug {
+            self.asm.push_str(&format!("{indent}num_buf resb 20\n"));
+        }
+        if input.is_some() {
+            self.asm.push_str(&format!("{indent}input_pos resq 1\n"));
+        }
+        if self.target_arch == "win64" {
+            self.asm.push_str(&format!("{indent}rt_written resq 1\n"));
+        }
+        self
+    }
+
+    /// Emits the failure stubs of the pending bounds checks in place, behind
+    /// a jump over them.
+    pub fn flush_bounds_stubs(mut self) -> Self {
+        if self.bounds_stubs.is_empty() {
+            return self;
+        }
+        let indent = if self.pretty { "    " } else { "" };
+        let id = self.bounds_checks;
+        self.asm
+            .push_str(&format!("{indent}jmp bounds_skip_{}\n", id));
+        self = self.bounds_fail_stubs();
+        self.asm.push_str(&format!("bounds_skip_{}:\n", id));
+        self
+    }
+
+    fn bounds_fail_stubs(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        if self.pretty {
+            self.asm
+                .push_str(&format!("\n{indent}; Bounds check failures\n"));
+        }
+        let first = self.bounds_checks - self.bounds_stubs.len();
+        for (i, (line, column)) in self.bounds_stubs.drain(..).enumerate() {
+            self.asm.push_str(&format!("bounds_fail_{}:\n", first + i));
+            self.asm.push_str(&format!("{indent}mov rbx, {}\n", line));
+            self.asm.push_str(&format!("{indent}mov r12, {}\n", column));
+            self.asm.push_str(&format!("{indent}jmp bounds_error\n"));
+        }
+        self
+    }
+
+    fn bounds_error_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        self = self.bounds_fail_stubs();
+        self.asm.push_str("bounds_error:\n");
+        self.asm.push_str(&format!("{indent}mov rax, bounds_msg\n"));
+        self.asm
+            .push_str(&format!("{indent}mov rdx, {}\n", BOUNDS_MSG.len()));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_stderr\n"));
+        self.asm.push_str(&format!("{indent}mov rax, rbx\n"));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_decimal\n"));
+        self.asm
+            .push_str(&format!("{indent}mov rax, bounds_column_msg\n"));
+        self.asm
+            .push_str(&format!("{indent}mov rdx, {}\n", BOUNDS_COLUMN_MSG.len()));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_stderr\n"));
+        self.asm.push_str(&format!("{indent}mov rax, r12\n"));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_decimal\n"));
+        self.asm.push_str(&format!("{indent}mov rax, newline\n"));
+        self.asm.push_str(&format!("{indent}mov rdx, 1\n"));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_stderr\n"));
+        self.exit_with(BOUNDS_EXIT_CODE)
+    }
+
+    // The kernel reports the faulting address in siginfo->si_addr; anything
+    // below the tape base hit the left guard page.
+    fn segv_handler_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        if self.pretty {
+            self.asm
+                .push_str(&format!("\n{indent}; Guard page fault handler\n"));
+        }
+        self.asm.push_str("segv_handler:\n");
+        for line in [
+            "mov rax, [rsi + 16]",
+            "cmp rax, [tape_base]",
+            "jb segv_left",
+            "mov rax, segv_right_msg",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm
+            .push_str(&format!("{indent}mov rdx, {}\n", SEGV_RIGHT_MSG.len() + 1));
+        self.asm.push_str(&format!("{indent}jmp segv_report\n"));
+        self.asm.push_str("segv_left:\n");
+        self.asm
+            .push_str(&format!("{indent}mov rax, segv_left_msg\n"));
+        self.asm
+            .push_str(&format!("{indent}mov rdx, {}\n", SEGV_LEFT_MSG.len() + 1));
+        self.asm.push_str("segv_report:\n");
+        self.asm
+            .push_str(&format!("{indent}call rt_write_stderr\n"));
+        self = self.exit_with(BOUNDS_EXIT_CODE);
+        // Never reached since the handler exits, but the kernel insists on one
+        self.asm.push_str("segv_restorer:\n");
+        self.asm.push_str(&format!("{indent}mov rax, 15\n"));
+        self.asm.push_str(&format!("{indent}syscall\n"));
+        self.asm.push_str("rt_map_failed:\n");
+        self.asm
+            .push_str(&format!("{indent}mov rax, map_failed_msg\n"));
+        self.asm
+            .push_str(&format!("{indent}mov rdx, {}\n", MAP_FAILED_MSG.len() + 1));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_stderr\n"));
+        self.exit_with(1)
+    }
+
+    // rt_grow_tape: doubles the tape until rsi is inside it again, moving
+    // rsi along with the tape and zeroing the new cells.
+    fn grow_tape_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        if self.pretty {
+            self.asm.push_str(&format!("\n{indent}; Tape growth\n"));
+        }
+        self.asm.push_str("rt_grow_tape:\n");
+        match self.target_arch.as_str() {
+            "unix" => {
+                for line in [
+                    "sub rsi, [tape_base]", // offset of the pointer
+                    "mov r8, rsi",
+                    "mov rsi, [tape_end]",
+                    "sub rsi, [tape_base]", // old size
+                    "mov rdx, rsi",
+                ] {
+                    self.asm.push_str(&format!("{indent}{line}\n"));
+                }
+                self.asm.push_str("rt_grow_double:\n");
+                for line in [
+                    "add rdx, rdx",
+                    "cmp r8, rdx",
+                    "jae rt_grow_double",
+                    "mov rdi, [tape_base]",
+                    "mov r10, 1",  // MREMAP_MAYMOVE
+                    "mov rax, 25", // sys_mremap
+                    "syscall",
+                    "cmp rax, -4095",
+                    "jae rt_grow_failed",
+                ] {
+                    self.asm.push_str(&format!("{indent}{line}\n"));
+                }
+            }
+            "win64" => {
+                for line in [
+                    "sub rsp, 56", // shadow space + 3 locals, keeps rsp 16-aligned
+                    "sub rsi, [tape_base]",
+                    "mov [rsp + 32], rsi", // offset of the pointer
+                    "mov rax, [tape_end]",
+                    "sub rax, [tape_base]",
+                    "mov [rsp + 40], rax", // old size
+                    "mov rdx, rax",
+                ] {
+                    self.asm.push_str(&format!("{indent}{line}\n"));
+                }
+                self.asm.push_str("rt_grow_double:\n");
+                for line in [
+                    "add rdx, rdx",
+                    "cmp [rsp + 32], rdx",
+                    "jae rt_grow_double",
+                    "mov [rsp + 48], rdx", // new size
+                    "mov rcx, [tape_base]",
+                    "call realloc",
+                    "test rax, rax",
+                    "jz rt_grow_failed",
+                    "mov [tape_base], rax",
+                    "mov rcx, rax",
+                    "add rcx, [rsp + 40]",
+                    "xor rdx, rdx",
+                    "mov r8, [rsp + 48]",
+                    "sub r8, [rsp + 40]",
+                    "call memset",
+                    "mov rax, [tape_base]",
+                    "mov rdx, [rsp + 48]",
+                    "mov r8, [rsp + 32]",
+                    "add rsp, 56",
+                ] {
+                    self.asm.push_str(&format!("{indent}{line}\n"));
+                }
+            }
+            _ => unreachable!("Unsupported target architecture"),
+        }
+        // rax = new base, rdx = new size, r8 = pointer offset
+        for line in [
+            "mov [tape_base], rax",
+            "add rdx, rax",
+            "mov [tape_end], rdx",
+            "lea rsi, [rax + r8]",
+            "ret",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_grow_failed:\n");
+        self.asm
+            .push_str(&format!("{indent}mov rax, grow_failed_msg\n"));
+        self.asm
+            .push_str(&format!("{indent}mov rdx, {}\n", GROW_FAILED_MSG.len() + 1));
+        self.asm
+            .push_str(&format!("{indent}call rt_write_stderr\n"));
+        self.exit_with(1)
+    }
+
+    fn exit_with(mut self, code: u8) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        match self.target_arch.as_str() {
+            "unix" => {
+                self.asm.push_str(&format!("{indent}mov rax, 60\n"));
+                self.asm.push_str(&format!("{indent}mov rdi, {}\n", code));
+                self.asm.push_str(&format!("{indent}syscall\n"));
+            }
+            "win64" => {
+                self.asm.push_str(&format!("{indent}mov rcx, {}\n", code));
+                self.asm.push_str(&format!("{indent}call ExitProcess\n"));
+            }
+            _ => unreachable!("Unsupported target architecture"),
+        }
+        self
+    }
+
+    // rt_read_input: copies the next byte of the embedded input to the
+    // current cell, or leaves it alone once the input is used up.
+    fn read_input_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        if self.pretty {
+            self.asm.push_str(&format!("\n{indent}; Embedded input\n"));
+        }
+        self.asm.push_str("rt_read_input:\n");
+        for line in [
+            "mov rax, [input_pos]",
+            "cmp rax, input_len",
+            "jae rt_read_input_eof",
+            "mov rdx, input_data",
+            "mov dl, [rdx + rax]",
+            "mov [rsi], dl",
+            "inc rax",
+            "mov [input_pos], rax",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_read_input_eof:\n");
+        self.asm.push_str(&format!("{indent}ret\n"));
+        self
+    }
+
+    // rt_read_input without embedded input: reads a byte from stdin into the
+    // current cell, the same as an inline `,`.
+    fn read_stdin_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        self.asm.push_str("rt_read_input:\n");
+        let body: &[&str] = match self.target_arch.as_str() {
+            "unix" => &[
+                "xor rax, rax",
+                "xor rdi, rdi",
+                "mov rdx, 1",
+                "syscall",
+                "ret",
+            ],
+            "win64" => &[
+                "sub rsp, 40", // shadow space, keeps rsp 16-aligned
+                "mov rcx, fmt_char",
+                "mov rdx, rsi",
+                "call scanf",
+                "add rsp, 40",
+                "ret",
+            ],
+            _ => unreachable!("Unsupported target architecture"),
+        };
+        for line in body {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self
+    }
+
+    // rt_debug_dump: writes `# pointer P, cells A-B: ...` to stderr, with the
+    // current cell in brackets. Preserves rsi and the callee-saved registers
+    // it uses to keep state across writes.
+    fn debug_dump_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        if self.pretty {
+            self.asm.push_str(&format!("\n{indent}; Debug dump\n"));
+        }
+        let (base, size): (&str, String) = if self.grow_tape {
+            ("[tape_base]", "[tape_end]".to_string())
+        } else if self.guard_pages {
+            ("[tape_base]", self.tape_size.to_string())
+        } else {
+            ("tape", self.tape_size.to_string())
+        };
+        self.asm.push_str("rt_debug_dump:\n");
+        // Five pushes leave rsp 16-byte aligned for the win64 calls
+        for line in [
+            "push rbx".to_string(),
+            "push r12".to_string(),
+            "push r13".to_string(),
+            "push r14".to_string(),
+            "push r15".to_string(),
+            format!("mov r15, {}", base),
+            "mov rbx, rsi".to_string(),
+            "sub rbx, r15".to_string(), // pointer offset
+            "mov r12, rbx".to_string(),
+            format!("and r12, -{}", DEBUG_WINDOW), // first cell shown
+            format!("lea r13, [r12 + {}]", DEBUG_WINDOW), // end of the window
+            format!("mov rax, {}", size),
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        if self.grow_tape {
+            self.asm.push_str(&format!("{indent}sub rax, r15\n"));
+        }
+        for line in ["cmp r13, rax", "jbe rt_debug_clamped", "mov r13, rax"] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_debug_clamped:\n");
+        for line in [
+            "mov rax, debug_pointer_msg".to_string(),
+            format!("mov rdx, {}", DEBUG_POINTER_MSG.len()),
+            "call rt_write_stderr".to_string(),
+            "mov rax, rbx".to_string(),
+            "call rt_write_decimal".to_string(),
+            // Nothing to show when the pointer is off the right end
+            "cmp r12, r13".to_string(),
+            "jae rt_debug_done".to_string(),
+            "mov rax, debug_cells_msg".to_string(),
+            format!("mov rdx, {}", DEBUG_CELLS_MSG.len()),
+            "call rt_write_stderr".to_string(),
+            "mov rax, r12".to_string(),
+            "call rt_write_decimal".to_string(),
+            "mov rax, debug_dash".to_string(),
+            "call rt_debug_char".to_string(),
+            "lea rax, [r13 - 1]".to_string(),
+            "call rt_write_decimal".to_string(),
+            "mov rax, debug_colon".to_string(),
+            "call rt_debug_char".to_string(),
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_debug_cell:\n");
+        for line in [
+            "mov rax, debug_space",
+            "call rt_debug_char",
+            "cmp r12, rbx",
+            "jne rt_debug_value",
+            "mov rax, debug_open",
+            "call rt_debug_char",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_debug_value:\n");
+        for line in [
+            "movzx rax, byte [r15 + r12]",
+            "call rt_write_decimal",
+            "cmp r12, rbx",
+            "jne rt_debug_next",
+            "mov rax, debug_close",
+            "call rt_debug_char",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_debug_next:\n");
+        for line in ["inc r12", "cmp r12, r13", "jb rt_debug_cell"] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self.asm.push_str("rt_debug_done:\n");
+        for line in [
+            "mov rax, newline",
+            "call rt_debug_char",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbx",
+            "ret",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        // rt_debug_char: writes the byte at rax
+        self.asm.push_str("rt_debug_char:\n");
+        self.asm.push_str(&format!("{indent}mov rdx, 1\n"));
+        self.asm.push_str(&format!("{indent}jmp rt_write_stderr\n"));
+        self
+    }
+
+    // rt_write_decimal: writes the unsigned value in rax to stderr.
+    fn write_decimal_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        self.asm.push_str("rt_write_decimal:\n");
+        self.asm
+            .push_str(&format!("{indent}mov rcx, num_buf + 20\n"));
+        self.asm.push_str(&format!("{indent}mov r9, 10\n"));
+        self.asm.push_str("rt_write_decimal_digit:\n");
+        for line in [
+            "xor rdx, rdx",
+            "div r9",
+            "add dl, 48",
+            "dec rcx",
+            "mov [rcx], dl",
+            "test rax, rax",
+            "jnz rt_write_decimal_digit",
+            "mov rax, rcx",
+            "mov rdx, num_buf + 20",
+            "sub rdx, rcx",
+            "jmp rt_write_stderr",
+        ] {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self
+    }
+
+    // rt_write_stderr: writes rdx bytes starting at rax to stderr.
+    // Preserves rsi so it is safe to call with a live tape pointer.
+    fn write_stderr_routine(mut self) -> Self {
+        let indent = if self.pretty { "    " } else { "" };
+        self.asm.push_str("rt_write_stderr:\n");
+        let body: &[&str] = match self.target_arch.as_str() {
+            "unix" => &[
+                "push rsi",
+                "mov rsi, rax",
+                "mov rax, 1",
+                "mov rdi, 2",
+                "syscall",
+                "pop rsi",
+                "ret",
+            ],
+            "win64" => &[
+                "push rsi",
+                "sub rsp, 48", // shadow space + 5th argument, keeps rsp 16-aligned
+                "mov rsi, rax",
+                "mov [rsp + 40], rdx",
+                "mov rcx, -12", // STD_ERROR_HANDLE
+                "call GetStdHandle",
+                "mov rcx, rax",
+                "mov rdx, rsi",
+                "mov r8, [rsp + 40]",
+                "mov r9, rt_written",
+                "mov qword [rsp + 32], 0",
+                "call WriteFile",
+                "add rsp, 48",
+                "pop rsi",
+                "ret",
+            ],
+            _ => unreachable!("Unsupported target architecture"),
+        };
+        for line in body {
+            self.asm.push_str(&format!("{indent}{line}\n"));
+        }
+        self
+    }
 }
 
 #[cfg(test)]
@@ -390,6 +1307,21 @@ mod tests {
         assert!(asm.contains("ret"));
     }
 
+    #[test]
+    fn test_footer_exit_cell() {
+        let asm = Assembler::new("unix", false, 10)
+            .with_exit_cell(true)
+            .footer()
+            .build();
+        assert!(asm.contains("movzx rdi, byte [rsi]"));
+        assert!(!asm.contains("xor rdi, rdi"));
+        let asm = Assembler::new("win64", false, 10)
+            .with_exit_cell(true)
+            .footer()
+            .build();
+        assert!(asm.contains("movzx eax, byte [rsi]"));
+    }
+
     #[test]
     fn test_inc_pointer() {
         let asm = Assembler::new("unix", false, 10).inc_pointer(3).build();
@@ -459,4 +1391,131 @@ mod tests {
         assert!(asm.contains("loop_start_99:"));
         assert!(asm.contains("loop_end_99:"));
     }
+
+    #[test]
+    fn test_check_bounds_unix() {
+        let asm = Assembler::new("unix", false, 10)
+            .dec_pointer(1)
+            .check_bounds(7, 3)
+            .footer()
+            .build();
+        assert!(asm.contains("cmp rax, 10"));
+        assert!(asm.contains("jae bounds_fail_0"));
+        assert!(asm.contains("bounds_fail_0:\nmov rbx, 7\nmov r12, 3\n"));
+        assert!(asm.contains("rt_write_stderr:"));
+        assert!(asm.contains(&format!("mov rdi, {}", BOUNDS_EXIT_CODE)));
+    }
+
+    #[test]
+    fn test_check_bounds_win64() {
+        let asm = Assembler::new("win64", false, 10)
+            .inc_pointer(1)
+            .check_bounds(1, 1)
+            .footer()
+            .build();
+        assert!(asm.contains("jae bounds_fail_0"));
+        assert!(asm.contains("call WriteFile"));
+        assert!(asm.contains("call ExitProcess"));
+    }
+
+    #[test]
+    fn test_guard_pages() {
+        let asm = Assembler::new("unix", false, 5000)
+            .with_guard_pages(true)
+            .header()
+            .footer()
+            .build();
+        assert!(!asm.contains("tape resb"));
+        assert!(asm.contains("tape_base resq 1"));
+        // two pages of tape plus a guard page either side
+        assert!(asm.contains("mov rsi, 16384"));
+        assert!(asm.contains("mov rsi, 8192"));
+        // The first cell is next to the left guard page
+        assert!(asm.contains("lea rdi, [rax + 4096]\nmov [tape_base], rdi\n"));
+        assert!(asm.contains("js rt_map_failed"));
+        assert!(asm.contains("rt_map_failed:"));
+        assert!(asm.contains("segv_handler:"));
+        assert!(asm.contains("segv_action dq segv_handler"));
+    }
+
+    #[test]
+    fn test_grow_tape_unix() {
+        let asm = Assembler::new("unix", false, 100)
+            .with_grow_tape(true)
+            .header()
+            .inc_pointer(1)
+            .inc_pointer(1)
+            .footer()
+            .build();
+        assert!(!asm.contains("tape resb"));
+        assert!(asm.contains("tape_end resq 1"));
+        assert!(asm.contains("jb grow_skip_1"));
+        assert!(asm.contains("mov rax, 25"));
+        assert!(asm.contains("rt_grow_tape:"));
+    }
+
+    #[test]
+    fn test_grow_tape_win64() {
+        let asm = Assembler::new("win64", false, 100)
+            .with_grow_tape(true)
+            .header()
+            .inc_pointer(1)
+            .footer()
+            .build();
+        assert!(asm.contains("call calloc"));
+        assert!(asm.contains("call realloc"));
+        assert!(asm.contains("call memset"));
+    }
+
+    #[test]
+    fn test_wrap_pointer_power_of_two() {
+        let asm = Assembler::new("unix", false, 256)
+            .with_wrap_pointer(true)
+            .inc_pointer(300)
+            .build();
+        assert!(asm.contains("add rsi, 44"));
+        assert!(asm.contains("and rsi, 255"));
+    }
+
+    #[test]
+    fn test_wrap_pointer() {
+        let asm = Assembler::new("win64", false, 100)
+            .with_wrap_pointer(true)
+            .inc_pointer(1)
+            .dec_pointer(1)
+            .dec_pointer(100)
+            .build();
+        assert!(asm.contains("cmovae rsi, rdx"));
+        assert!(asm.contains("cmovb rsi, rdx"));
+        assert_eq!(asm.matches("add rsi, rax").count(), 2);
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let asm = Assembler::new("unix", false, 100)
+            .header()
+            .debug_dump()
+            .footer()
+            .build();
+        assert!(asm.contains("call rt_debug_dump\n"));
+        assert!(asm.contains("rt_debug_dump:\n"));
+        assert!(asm.contains("mov r15, tape\n"));
+        assert!(asm.contains("mov rax, 100\n"));
+        assert!(asm.contains("rt_write_decimal:"));
+        assert!(asm.contains("newline db 10"));
+        let asm = Assembler::new("unix", false, 100)
+            .with_grow_tape(true)
+            .header()
+            .debug_dump()
+            .footer()
+            .build();
+        assert!(asm.contains("mov r15, [tape_base]\nmov rbx, rsi\n"));
+        assert!(asm.contains("mov rax, [tape_end]\nsub rax, r15\n"));
+    }
+
+    #[test]
+    fn test_no_runtime_without_checks() {
+        let asm = Assembler::new("unix", false, 10).footer().build();
+        assert!(!asm.contains("rt_write_stderr"));
+    }
 }
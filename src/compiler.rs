@@ -1,60 +1,253 @@
-use crate::asm::Assembler;
-use crate::lexer::BFToken;
+use crate::asm::{Assembler, BOUNDS_STUB_CHUNK, TARGETS};
+use crate::diagnostic::Diagnostic;
+use crate::error::Error;
+use crate::ir::{Instr, Lowering, Op, lower, optimize};
+use crate::lexer::{BFLexer, Token};
+use std::io::Write;
+use std::path::Path;
 
 pub struct BFCompiler {
-    pub tokens: Vec<BFToken>,
+    pub tokens: Vec<Token>,
     pub tape_size: usize,
     pub target_arch: String,
     pub pretty: bool,
+    pub bounds_check: bool,
+    pub guard_pages: bool,
+    pub grow_tape: bool,
+    pub wrap_pointer: bool,
+    pub exit_cell: bool,
+    /// Input baked into the executable instead of reading stdin
+    pub input: Option<Vec<u8>>,
 }
 
 impl BFCompiler {
-    pub fn new(tokens: Vec<BFToken>, tape_size: usize, target_arch: &str, pretty: bool) -> Self {
+    pub fn new<T: Into<Token>>(
+        tokens: Vec<T>,
+        tape_size: usize,
+        target_arch: &str,
+        pretty: bool,
+    ) -> Self {
         BFCompiler {
-            tokens,
+            tokens: tokens.into_iter().map(Into::into).collect(),
             tape_size,
             target_arch: target_arch.to_string(),
             pretty,
+            bounds_check: false,
+            guard_pages: false,
+            grow_tape: false,
+            wrap_pointer: false,
+            exit_cell: false,
+            input: None,
         }
     }
-    pub fn compile(&self) -> String {
-        let mut assembler = Assembler::new(&self.target_arch, self.pretty, self.tape_size).header();
-        let mut loop_stack = Vec::new();
-        let mut loop_id = 0;
-        for token in &self.tokens {
-            match token {
-                BFToken::IncrementPointer(n) => {
-                    assembler = assembler.inc_pointer(*n);
-                }
-                BFToken::DecrementPointer(n) => {
-                    assembler = assembler.dec_pointer(*n);
-                }
-                BFToken::IncrementValue(n) => {
-                    assembler = assembler.inc_value(*n);
-                }
-                BFToken::DecrementValue(n) => {
-                    assembler = assembler.dec_value(*n);
-                }
-                BFToken::OutputValue(n) => {
-                    assembler = assembler.output_value(*n);
-                }
-                BFToken::InputValue(n) => {
-                    assembler = assembler.input_value(*n);
+
+    /// A compiler for `compile_stream`, which reads the program from a lexer
+    /// rather than taking its tokens up front.
+    pub fn streaming(tape_size: usize, target_arch: &str, pretty: bool) -> Self {
+        BFCompiler::new(Vec::<Token>::new(), tape_size, target_arch, pretty)
+    }
+
+    /// Read `,` from `input` instead of stdin.
+    pub fn with_input(mut self, input: Option<Vec<u8>>) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Exit with the value of the current cell when the program ends.
+    pub fn with_exit_cell(mut self, exit_cell: bool) -> Self {
+        self.exit_cell = exit_cell;
+        self
+    }
+
+    /// Wrap the pointer around the ends of the tape.
+    pub fn with_wrap_pointer(mut self, wrap_pointer: bool) -> Self {
+        self.wrap_pointer = wrap_pointer;
+        self
+    }
+
+    /// Start with `tape_size` cells and grow the tape on demand.
+    pub fn with_grow_tape(mut self, grow_tape: bool) -> Self {
+        self.grow_tape = grow_tape;
+        self
+    }
+
+    /// Surround the tape with guard pages that fault on out of range access.
+    pub fn with_guard_pages(mut self, guard_pages: bool) -> Self {
+        self.guard_pages = guard_pages;
+        self
+    }
+
+    /// Check the pointer against both ends of the tape after every move.
+    pub fn with_bounds_check(mut self, bounds_check: bool) -> Self {
+        self.bounds_check = bounds_check;
+        self
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if !TARGETS.contains(&self.target_arch.as_str()) {
+            return Err(Error::UnsupportedTarget(self.target_arch.clone()));
+        }
+        if self.tape_size == 0 {
+            return Err(Error::InvalidOptions(
+                "The tape needs at least one cell".to_string(),
+            ));
+        }
+        if self.guard_pages && self.target_arch != "unix" {
+            return Err(Error::InvalidOptions(
+                "Guard pages are only supported on unix".to_string(),
+            ));
+        }
+        if self.grow_tape && (self.guard_pages || self.wrap_pointer) {
+            return Err(Error::InvalidOptions(
+                "A growable tape cannot be combined with guard pages or a wrapping pointer"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn assembler(&self) -> Assembler {
+        Assembler::new(&self.target_arch, self.pretty, self.tape_size)
+            .with_guard_pages(self.guard_pages)
+            .with_grow_tape(self.grow_tape)
+            .with_wrap_pointer(self.wrap_pointer)
+            .with_exit_cell(self.exit_cell)
+            .with_input(self.input.clone())
+    }
+
+    /// The tokens as instructions, before optimization.
+    pub fn ir(&self) -> Vec<Instr> {
+        lower(&self.tokens)
+    }
+
+    /// The instructions `compile` generates code for.
+    pub fn optimized_ir(&self) -> Vec<Instr> {
+        optimize(&self.ir(), self.bounds_check)
+    }
+
+    pub fn compile(&self) -> Result<String, Error> {
+        self.validate()?;
+        let mut assembler = self.assembler().header();
+        for instr in &self.optimized_ir() {
+            assembler = self.emit(assembler, instr);
+        }
+        assembler = assembler.footer();
+        Ok(assembler.build())
+    }
+
+    /// Compiles tokens as `lexer` reads them and writes the assembly to
+    /// `out` as it goes, so memory use doesn't grow with the length of the
+    /// program. Runs are not folded as by `optimized_ir`. Brackets are
+    /// matched on the fly, and all syntax errors are reported once the input
+    /// is exhausted. Input after a `!` separator is embedded as with
+    /// `with_input`. `path` names `out` in write errors.
+    pub fn compile_stream<I, W>(
+        &self,
+        lexer: &mut BFLexer<I>,
+        out: &mut W,
+        path: &Path,
+    ) -> Result<(), Error>
+    where
+        I: Iterator<Item = char>,
+        W: Write,
+    {
+        self.validate()?;
+        let mut flush = |assembler: &mut Assembler| {
+            out.write_all(assembler.asm.as_bytes())
+                .map_err(|e| Error::io(path, e))?;
+            assembler.asm.clear();
+            Ok::<(), Error>(())
+        };
+        // Whether `,` reads embedded input is only known at the end
+        let mut assembler = self
+            .assembler()
+            .with_deferred_input(lexer.input_separator())
+            .header();
+        let mut loops = Lowering::default();
+        let mut diagnostics = Vec::new();
+        for token in lexer.by_ref() {
+            let token = match token {
+                Ok(token) => token,
+                Err(Error::Syntax(errors)) => {
+                    diagnostics.extend(errors);
+                    continue;
                 }
-                BFToken::LoopStart => {
-                    assembler = assembler.loop_start(loop_id);
-                    loop_stack.push(loop_id);
-                    loop_id += 1;
+                Err(e) => return Err(e),
+            };
+            let Some(instr) = loops.lower(&token) else {
+                diagnostics.push(
+                    Diagnostic::error("unmatched `]`", token.span)
+                        .with_label("this `]` has no opening `[`"),
+                );
+                continue;
+            };
+            assembler = self.emit(assembler, &instr);
+            if assembler.bounds_stubs.len() >= BOUNDS_STUB_CHUNK {
+                assembler = assembler.flush_bounds_stubs();
+            }
+            flush(&mut assembler)?;
+        }
+        for (_, span) in &loops.open {
+            diagnostics.push(
+                Diagnostic::error("unmatched `[`", *span).with_label("this `[` was never closed"),
+            );
+        }
+        if !diagnostics.is_empty() {
+            diagnostics.sort_by_key(|d| d.span.offset);
+            return Err(Error::Syntax(diagnostics));
+        }
+        if lexer.input_separator() {
+            let input = lexer
+                .embedded_input()
+                .map(|input| input.as_bytes().to_vec());
+            assembler = assembler.with_input(input);
+        }
+        assembler = assembler.footer();
+        flush(&mut assembler)
+    }
+
+    fn emit(&self, mut assembler: Assembler, instr: &Instr) -> Assembler {
+        let span = instr.span;
+        match instr.op {
+            Op::Move(n) => {
+                // Folded moves can be longer than one token allows
+                let mut left = n.unsigned_abs();
+                while left > 0 {
+                    let step = left.min(u32::MAX as u64) as u32;
+                    assembler = if n > 0 {
+                        assembler.inc_pointer(step)
+                    } else {
+                        assembler.dec_pointer(step)
+                    };
+                    left -= step as u64;
                 }
-                BFToken::LoopEnd => {
-                    if let Some(id) = loop_stack.pop() {
-                        assembler = assembler.loop_end(id);
-                    }
+                if self.bounds_check {
+                    assembler = assembler.check_bounds(span.line, span.column);
                 }
             }
+            Op::Add(n) if n < 0 => {
+                assembler = assembler.dec_value(n.unsigned_abs() as u32);
+            }
+            Op::Add(n) => {
+                assembler = assembler.inc_value(n as u32);
+            }
+            Op::Output(n) => {
+                assembler = assembler.output_value(n);
+            }
+            Op::Input(n) => {
+                assembler = assembler.input_value(n);
+            }
+            Op::LoopStart(id) => {
+                assembler = assembler.loop_start(id);
+            }
+            Op::LoopEnd(id) => {
+                assembler = assembler.loop_end(id);
+            }
+            Op::DebugDump => {
+                assembler = assembler.debug_dump();
+            }
         }
-        assembler = assembler.footer();
-        assembler.build()
+        assembler
     }
 }
 
@@ -66,7 +259,7 @@ mod tests {
     #[test]
     fn test_increment_pointer() {
         let compiler = BFCompiler::new(vec![BFToken::IncrementPointer(3)], 90000, "unix", true);
-        let asm = compiler.compile();
+        let asm = compiler.compile().unwrap();
         assert!(asm.contains("add rsi, 3"));
     }
 
@@ -78,7 +271,7 @@ mod tests {
             "unix",
             true,
         );
-        let asm = compiler.compile();
+        let asm = compiler.compile().unwrap();
         assert!(asm.contains("sub byte [rsi], 2"));
         assert!(asm.contains("sys_write"));
     }
@@ -95,7 +288,7 @@ mod tests {
             "unix",
             true,
         );
-        let asm = compiler.compile();
+        let asm = compiler.compile().unwrap();
         assert!(asm.contains("loop_start_0:"));
         assert!(asm.contains("loop_end_0:"));
         assert!(asm.contains("add byte [rsi], 1"));
@@ -104,8 +297,166 @@ mod tests {
     #[test]
     fn test_input_and_exit() {
         let compiler = BFCompiler::new(vec![BFToken::InputValue(1)], 90000, "unix", true);
-        let asm = compiler.compile();
+        let asm = compiler.compile().unwrap();
         assert!(asm.contains("sys_read"));
         assert!(asm.contains("sys_exit"));
     }
+
+    #[test]
+    fn test_bounds_check() {
+        let tokens = BFLexer::new("+\n <".chars()).tokenize().unwrap();
+        let asm = BFCompiler::new(tokens.clone(), 100, "unix", false)
+            .compile()
+            .unwrap();
+        assert!(!asm.contains("bounds_error"));
+        let asm = BFCompiler::new(tokens, 100, "unix", false)
+            .with_bounds_check(true)
+            .compile()
+            .unwrap();
+        assert!(asm.contains("jae bounds_fail_0"));
+        assert!(asm.contains("bounds_fail_0:\nmov rbx, 2\nmov r12, 2\n"));
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let asm = BFCompiler::new(vec![BFToken::DebugDump], 100, "unix", false)
+            .compile()
+            .unwrap();
+        assert!(asm.contains("call rt_debug_dump"));
+        assert!(asm.contains("rt_debug_dump:"));
+        let asm = BFCompiler::new(vec![BFToken::IncrementValue(1)], 100, "unix", false)
+            .compile()
+            .unwrap();
+        assert!(!asm.contains("rt_debug_dump"));
+    }
+
+    #[test]
+    fn test_embedded_input() {
+        let asm = BFCompiler::new(vec![BFToken::InputValue(2)], 100, "unix", false)
+            .with_input(Some(b"hi".to_vec()))
+            .compile()
+            .unwrap();
+        assert!(!asm.contains("syscall\nsyscall"));
+        assert!(asm.contains("call rt_read_input\ncall rt_read_input\n"));
+        assert!(asm.contains("input_data db 104, 105\n"));
+    }
+
+    #[test]
+    fn test_compile_stream() {
+        let source = "+[->+<]>.";
+        let compiler = BFCompiler::streaming(100, "unix", false);
+        let mut out = Vec::new();
+        compiler
+            .compile_stream(
+                &mut BFLexer::new(source.chars()),
+                &mut out,
+                Path::new("out"),
+            )
+            .unwrap();
+        let tokens = BFLexer::new(source.chars()).tokenize().unwrap();
+        let expected = BFCompiler::new(tokens, 100, "unix", false)
+            .compile()
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_compile_stream_bounds_stubs() {
+        let source = "+>".repeat(BOUNDS_STUB_CHUNK + 1);
+        let mut out = Vec::new();
+        BFCompiler::streaming(100, "unix", false)
+            .with_bounds_check(true)
+            .compile_stream(
+                &mut BFLexer::new(source.chars()),
+                &mut out,
+                Path::new("out"),
+            )
+            .unwrap();
+        let asm = String::from_utf8(out).unwrap();
+        // A full chunk of stubs is emitted in place, the rest at the end
+        let skip = format!("jmp bounds_skip_{0}\nbounds_fail_0:\n", BOUNDS_STUB_CHUNK);
+        assert!(asm.contains(&skip));
+        let last = format!("bounds_fail_{}:\n", BOUNDS_STUB_CHUNK);
+        assert!(asm.find(&last).unwrap() > asm.find("mov rax, 60\n").unwrap());
+        assert_eq!(asm.matches("bounds_error:\n").count(), 1);
+    }
+
+    #[test]
+    fn test_compile_stream_unmatched() {
+        let compiler = BFCompiler::streaming(100, "unix", false);
+        let mut out = Vec::new();
+        let result = compiler.compile_stream(
+            &mut BFLexer::new("]+[\n[-]".chars()),
+            &mut out,
+            Path::new("out"),
+        );
+        match result {
+            Err(Error::Syntax(diagnostics)) => {
+                let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                assert_eq!(
+                    messages,
+                    vec![
+                        "unmatched `]` at line 1, column 1",
+                        "unmatched `[` at line 1, column 3",
+                    ]
+                );
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compile_stream_input_separator() {
+        let stream = |source: &str| {
+            let mut out = Vec::new();
+            BFCompiler::streaming(100, "unix", false)
+                .compile_stream(
+                    &mut BFLexer::new(source.chars()).with_input_separator(true),
+                    &mut out,
+                    Path::new("out"),
+                )
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let asm = stream(",.!hi");
+        assert!(asm.contains("call rt_read_input\n"));
+        assert!(asm.contains("input_data db 104, 105\n"));
+        // Without a `!` the input routine reads stdin
+        let asm = stream(",.");
+        assert!(asm.contains("call rt_read_input\n"));
+        assert!(!asm.contains("input_data"));
+        assert!(
+            asm.contains("rt_read_input:\nxor rax, rax\nxor rdi, rdi\nmov rdx, 1\nsyscall\nret\n")
+        );
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let compiler = BFCompiler::new(vec![BFToken::IncrementValue(1)], 100, "mips", false);
+        assert!(matches!(
+            compiler.compile(),
+            Err(Error::UnsupportedTarget(_))
+        ));
+        let compiler = BFCompiler::new(vec![BFToken::IncrementValue(1)], 100, "win64", false)
+            .with_guard_pages(true);
+        assert!(matches!(compiler.compile(), Err(Error::InvalidOptions(_))));
+        let compiler = BFCompiler::new(vec![BFToken::IncrementPointer(1)], 0, "unix", false)
+            .with_wrap_pointer(true);
+        assert!(matches!(compiler.compile(), Err(Error::InvalidOptions(_))));
+        for compiler in [
+            BFCompiler::streaming(100, "unix", false)
+                .with_grow_tape(true)
+                .with_guard_pages(true),
+            BFCompiler::streaming(100, "unix", false)
+                .with_grow_tape(true)
+                .with_wrap_pointer(true),
+        ] {
+            let result = compiler.compile_stream(
+                &mut BFLexer::new("+".chars()),
+                &mut Vec::new(),
+                Path::new("out"),
+            );
+            assert!(matches!(result, Err(Error::InvalidOptions(_))));
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use crate::lexer::Span;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A secondary message, optionally pointing at another place in the source.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+/// A message about a span of source code, rendered rustc-style by `render`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    /// Text printed next to the caret underline
+    pub label: Option<String>,
+    pub notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            label: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message, span)
+        }
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note(mut self, message: impl Into<String>, span: Option<Span>) -> Self {
+        self.notes.push(Note {
+            message: message.into(),
+            span,
+        });
+        self
+    }
+
+    /// Renders the diagnostic with the offending source lines and a caret
+    /// underline, e.g.
+    ///
+    /// ```text
+    /// error: unmatched `[`
+    ///  --> prog.b:2:3
+    ///   |
+    /// 2 |   [->+<
+    ///   |   ^ this `[` was never closed
+    /// ```
+    pub fn render(&self, source: &str, filename: &str) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let lines = std::iter::once(self.span.line)
+            .chain(self.notes.iter().filter_map(|n| n.span.map(|s| s.line)));
+        let width = lines.max().unwrap_or(0).to_string().len();
+
+        let mut out = format!("{}: {}\n", severity, self.message);
+        out.push_str(&snippet(
+            source,
+            filename,
+            self.span,
+            self.label.as_deref(),
+            width,
+        ));
+        for note in &self.notes {
+            out.push_str(&format!("{:width$} = note: {}\n", "", note.message));
+            if let Some(span) = note.span {
+                out.push_str(&snippet(source, filename, span, None, width));
+            }
+        }
+        out
+    }
+}
+
+// The ` --> file:line:col` header plus the source line with carets under
+// `span`. Spans without a location (line 0), or whose line is not in
+// `source`, e.g. because stdin was streamed, only get the header.
+fn snippet(source: &str, filename: &str, span: Span, label: Option<&str>, width: usize) -> String {
+    if span.line == 0 {
+        return format!("{:width$}--> {}\n", "", filename);
+    }
+    let mut out = format!(
+        "{:width$}--> {}:{}:{}\n",
+        "", filename, span.line, span.column
+    );
+    let Some(text) = source.lines().nth(span.line - 1) else {
+        return out;
+    };
+    let start = span.column - 1;
+    // Underline at most to the end of the line
+    let carets = source
+        .get(span.offset..span.offset + span.len)
+        .map(|s| s.lines().next().unwrap_or("").chars().count())
+        .unwrap_or(1)
+        .max(1);
+    out.push_str(&format!("{:width$} |\n", ""));
+    out.push_str(&format!("{:>width$} | {}\n", span.line, text));
+    out.push_str(&format!(
+        "{:width$} | {}{}{}\n",
+        "",
+        " ".repeat(start),
+        "^".repeat(carets),
+        label.map(|l| format!(" {}", l)).unwrap_or_default()
+    ));
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.span.line, self.span.column
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(offset: usize, line: usize, column: usize, len: usize) -> Span {
+        Span {
+            offset,
+            line,
+            column,
+            len,
+        }
+    }
+
+    #[test]
+    fn test_render_with_label() {
+        let source = "+\n  [->+<\n";
+        let diagnostic =
+            Diagnostic::error("unmatched `[`", span(4, 2, 3, 1)).with_label("never closed");
+        assert_eq!(
+            diagnostic.render(source, "prog.b"),
+            "error: unmatched `[`\n --> prog.b:2:3\n  |\n2 |   [->+<\n  |   ^ never closed\n"
+        );
+    }
+
+    #[test]
+    fn test_render_note_and_width() {
+        let source = format!("{}+++\n]", "\n".repeat(9));
+        let diagnostic = Diagnostic::warning("useless", span(9, 10, 1, 3))
+            .with_note("see here", Some(span(13, 11, 1, 1)));
+        let rendered = diagnostic.render(&source, "a.b");
+        assert!(rendered.starts_with("warning: useless\n  --> a.b:10:1\n"));
+        assert!(rendered.contains("10 | +++\n   | ^^^\n"));
+        assert!(rendered.contains("   = note: see here\n  --> a.b:11:1\n"));
+    }
+
+    #[test]
+    fn test_render_without_source() {
+        let diagnostic =
+            Diagnostic::error("unmatched `[`", span(4, 2, 3, 1)).with_label("never closed");
+        assert_eq!(
+            diagnostic.render("", "<stdin>"),
+            "error: unmatched `[`\n --> <stdin>:2:3\n"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let diagnostic = Diagnostic::error("unmatched `]`", span(0, 3, 2, 1));
+        assert_eq!(diagnostic.to_string(), "unmatched `]` at line 3, column 2");
+    }
+}
@@ -0,0 +1,129 @@
+/// Line diff between `old` and `new` in unified format without context
+/// lines, like `diff -U0`. Empty when they are equal.
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let hunks = hunks(&old, &new);
+    if hunks.is_empty() {
+        return String::new();
+    }
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for hunk in hunks {
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(hunk.old_start, hunk.old_len),
+            range(hunk.new_start, hunk.new_len)
+        ));
+        for line in &old[hunk.old_start..hunk.old_start + hunk.old_len] {
+            out.push_str(&format!("-{}\n", line));
+        }
+        for line in &new[hunk.new_start..hunk.new_start + hunk.new_len] {
+            out.push_str(&format!("+{}\n", line));
+        }
+    }
+    out
+}
+
+// `start,len` with 1-based lines; an empty range names the line before it
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+// Largest LCS table worth computing; bigger changes become a single hunk
+const MAX_TABLE: usize = 1 << 22;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Hunk {
+    old_start: usize,
+    old_len: usize,
+    new_start: usize,
+    new_len: usize,
+}
+
+// Changed regions between two line lists, from a longest common subsequence
+// of the lines between the common prefix and suffix
+fn hunks(old: &[&str], new: &[&str]) -> Vec<Hunk> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+    if a.len() * b.len() > MAX_TABLE {
+        return vec![Hunk {
+            old_start: prefix,
+            old_len: a.len(),
+            new_start: prefix,
+            new_len: b.len(),
+        }];
+    }
+
+    // lcs[i][j] is the LCS length of a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let (start_i, start_j) = (i, j);
+        while (i < a.len() || j < b.len()) && !(i < a.len() && j < b.len() && a[i] == b[j]) {
+            if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        hunks.push(Hunk {
+            old_start: prefix + start_i,
+            old_len: i - start_i,
+            new_start: prefix + start_j,
+            new_len: j - start_j,
+        });
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equal() {
+        assert_eq!(unified_diff("+\n-\n", "+\n-\n", "a", "b"), "");
+    }
+
+    #[test]
+    fn test_changes() {
+        let diff = unified_diff("+\n-\n.\n", "+\nx\n.\n>\n", "a.b", "a.b (formatted)");
+        assert_eq!(
+            diff,
+            "--- a.b\n+++ a.b (formatted)\n@@ -2 +2 @@\n--\n+x\n@@ -3,0 +4 @@\n+>\n"
+        );
+    }
+
+    #[test]
+    fn test_deletion() {
+        let diff = unified_diff("+\n-\n-\n.\n", "+\n.\n", "a", "b");
+        assert_eq!(diff, "--- a\n+++ b\n@@ -2,2 +1,0 @@\n--\n--\n");
+    }
+}
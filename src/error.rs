@@ -0,0 +1,97 @@
+use crate::diagnostic::Diagnostic;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Everything that can go wrong between reading a source file and linking
+/// the executable.
+#[derive(Debug)]
+pub enum Error {
+    /// The source is not a valid program, e.g. unbalanced brackets
+    Syntax(Vec<Diagnostic>),
+    /// Reading or writing a file failed
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A required external tool (`nasm`, `ld`) is not in PATH
+    ToolchainMissing(String),
+    /// NASM ran but rejected the generated assembly
+    AssemblerFailed {
+        target: String,
+        /// What NASM printed
+        stderr: String,
+    },
+    /// ld ran but could not produce an executable
+    LinkerFailed {
+        target: String,
+        /// What ld printed
+        stderr: String,
+    },
+    /// The target is not one of `asm::TARGETS`
+    UnsupportedTarget(String),
+    /// Options that cannot be combined, or are not available on the target
+    InvalidOptions(String),
+    /// `fmt --check` found a file that formatting would change
+    Unformatted(String),
+    /// `bf test` ran programs whose output was not the expected output
+    TestsFailed(usize),
+    /// Some of several programs built at once failed to build
+    BuildsFailed(usize),
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syntax(diagnostics) => {
+                let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::ToolchainMissing(tool) => {
+                write!(f, "{} is not installed or not found in PATH", tool)
+            }
+            Error::AssemblerFailed { target, stderr } => {
+                write!(f, "NASM failed to assemble the code for {}", target)?;
+                with_output(f, stderr)
+            }
+            Error::LinkerFailed { target, stderr } => {
+                write!(f, "ld failed to link the object file for {}", target)?;
+                with_output(f, stderr)
+            }
+            Error::UnsupportedTarget(target) => {
+                write!(f, "Unknown target architecture: {}", target)
+            }
+            Error::InvalidOptions(message) => write!(f, "{}", message),
+            Error::Unformatted(path) => write!(f, "{} is not formatted", path),
+            Error::TestsFailed(count) => write!(f, "{} test(s) failed", count),
+            Error::BuildsFailed(count) => write!(f, "{} build(s) failed", count),
+        }
+    }
+}
+
+// Appends a tool's output below the message, if it printed anything
+fn with_output(f: &mut fmt::Formatter<'_>, output: &str) -> fmt::Result {
+    if output.is_empty() {
+        Ok(())
+    } else {
+        write!(f, ":\n{}", output)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -1,80 +1,617 @@
-pub fn format_code(source: &str) -> String {
-    let mut tabs: i32 = 0;
-    let mut formatted = String::new();
-    let mut chars = source.chars().peekable();
-    let mut last_type: Option<char> = None;
-    let mut first_bracket = true;
-    let bf_commands = ['>', '<', '+', '-', '.', ',', '[', ']'];
-    let mut need_tab = false;
-    while let Some(c) = chars.next() {
-        if c == '\n' || c == '\r' || (!bf_commands.contains(&c) && !c.is_ascii_digit()) {
-            continue; // filter out newlines, comments, and non-command chars
-        }
-        let is_command = bf_commands.contains(&c);
-        if is_command {
-            if let Some(last) = last_type {
-                if last != c {
-                    formatted.push('\n');
-                    need_tab = true;
-                }
+use crate::error::Error;
+use crate::lexer::{BFLexer, BFToken, Dialect, Span};
+
+/// Longest run of `>`, `<`, `.` or `,` written out one command at a time.
+/// Longer counts are errors rather than megabytes of output.
+pub const MAX_EXPANDED_RUN: u64 = 1 << 20;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IndentStyle {
+    Tabs,
+    Spaces,
+}
+
+/// What to do with repeat counts. Only has an effect in the extended dialect.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Counts {
+    /// Leave runs as written
+    Keep,
+    /// Write runs longer than one command as `+5`
+    Compress,
+    /// Write `+5` as `+++++`
+    Expand,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BlankLines {
+    /// Blank line after every `[` and before every `]` that are on their own
+    /// lines, plus the ones in the source
+    AroundLoops,
+    /// Only the blank lines in the source, at most one in a row
+    Preserve,
+    None,
+}
+
+/// Layout settings for `format_with`. The default is the classic style: one
+/// run per line, tab indents and blank lines around loop bodies.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FormatOptions {
+    pub indent_style: IndentStyle,
+    /// Spaces per level, also the width of a tab when measuring lines
+    pub indent_width: usize,
+    /// Pack commands onto lines of at most this many columns. Loops that fit
+    /// stay on one line. 0 puts every run on its own line.
+    pub max_width: usize,
+    pub counts: Counts,
+    pub blank_lines: BlankLines,
+    /// Stop at the first `!` and copy the program's input after it as is,
+    /// like the lexer's `with_input_separator`. Not read from config files,
+    /// since it depends on how the source is compiled.
+    pub input_separator: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent_style: IndentStyle::Tabs,
+            indent_width: 4,
+            max_width: 0,
+            counts: Counts::Keep,
+            blank_lines: BlankLines::AroundLoops,
+            input_separator: false,
+        }
+    }
+}
+
+impl FormatOptions {
+    /// Reads `key = value` lines as found in `.bffmt`, or in the `[format]`
+    /// table of `bf.toml` when `table` is given. Other tables, comments and
+    /// blank lines are skipped; unknown keys are errors.
+    pub fn parse_config(text: &str, table: Option<&str>) -> Result<FormatOptions, String> {
+        let mut options = FormatOptions::default();
+        let mut current: Option<String> = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                current = Some(name.trim().to_string());
+                continue;
             }
-            match c {
-                '[' => {
-                    if first_bracket {
-                        formatted.push('[');
-                        formatted.push('\n');
-                        tabs += 1;
-                        first_bracket = false;
-                        need_tab = true;
-                    } else {
-                        if need_tab {
-                            for _ in 0..tabs {
-                                formatted.push('\t');
-                            }
-                            //need_tab = false;
-                        }
-                        formatted.push('[');
-                        formatted.push('\n');
-                        tabs += 1;
-                        need_tab = true;
+            if current.as_deref() != table {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected `key = value`", i + 1))?;
+            let (key, value) = (key.trim(), value.trim().trim_matches('"'));
+            let invalid = || format!("line {}: invalid value `{}` for `{}`", i + 1, value, key);
+            match key {
+                "indent_style" => {
+                    options.indent_style = match value {
+                        "tabs" => IndentStyle::Tabs,
+                        "spaces" => IndentStyle::Spaces,
+                        _ => return Err(invalid()),
                     }
                 }
-                ']' => {
-                    tabs = tabs.saturating_sub(1);
-                    formatted.push('\n');
-                    for _ in 0..tabs {
-                        formatted.push('\t');
+                "indent_width" => options.indent_width = value.parse().map_err(|_| invalid())?,
+                "max_width" => options.max_width = value.parse().map_err(|_| invalid())?,
+                "counts" => {
+                    options.counts = match value {
+                        "keep" => Counts::Keep,
+                        "compress" => Counts::Compress,
+                        "expand" => Counts::Expand,
+                        _ => return Err(invalid()),
                     }
-                    formatted.push(']');
-                    need_tab = false;
                 }
-                _ => {
-                    if need_tab {
-                        for _ in 0..tabs {
-                            formatted.push('\t');
-                        }
-                        need_tab = false;
+                "blank_lines" => {
+                    options.blank_lines = match value {
+                        "around-loops" => BlankLines::AroundLoops,
+                        "preserve" => BlankLines::Preserve,
+                        "none" => BlankLines::None,
+                        _ => return Err(invalid()),
                     }
-                    formatted.push(c);
+                }
+                _ => return Err(format!("line {}: unknown key `{}`", i + 1, key)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Run {
+    command: char,
+    /// Source text: the command, repeated, plus any digits right after it
+    text: String,
+    /// Times the command runs
+    count: u32,
+}
+
+impl Run {
+    fn render(&self, counts: Counts) -> String {
+        match (counts, self.count) {
+            (Counts::Compress, 1) => self.command.to_string(),
+            (Counts::Compress, n) => format!("{}{}", self.command, n),
+            (Counts::Expand, n) => self.command.to_string().repeat(n as usize),
+            (Counts::Keep, _) => self.text.clone(),
+        }
+    }
+}
+
+// A piece of the source: a run of one command, a comment up to the end of
+// its line, or a blank line
+#[derive(Debug, Clone)]
+enum Item {
+    Run(Run),
+    /// Text and whether it follows code on the same source line
+    Comment(String, bool),
+    Blank,
+}
+
+// Splits the source into items using the lexer's tokens, so runs and counts
+// are exactly what the compiler reads. Everything between two tokens is
+// whitespace or comments.
+fn parse(source: &str, dialect: Dialect, counts: Counts) -> Result<Vec<Item>, Error> {
+    let tokens = BFLexer::new(source.chars())
+        .with_dialect(dialect)
+        .tokenize()?;
+    let mut items: Vec<Item> = Vec::new();
+    let mut newlines = 0;
+    // Whether the current source line has code, so a comment trails it
+    let mut code_on_line = false;
+    let mut end = 0;
+    for token in tokens.iter().map(Some).chain([None]) {
+        let start = token.map_or(source.len(), |t| t.span.offset);
+        for (i, line) in source[end..start].split('\n').enumerate() {
+            if i > 0 {
+                newlines += 1;
+                code_on_line = false;
+            }
+            let comment = line.trim();
+            if !comment.is_empty() {
+                if newlines >= 2 {
+                    items.push(Item::Blank);
+                }
+                items.push(Item::Comment(comment.to_string(), code_on_line));
+                newlines = 0;
+            }
+        }
+        let Some(token) = token else {
+            break;
+        };
+        end = token.span.offset + token.span.len;
+        let text = &source[token.span.offset..end];
+        let command = text.chars().next().unwrap_or_default();
+        let count = match token.kind {
+            BFToken::IncrementPointer(n)
+            | BFToken::DecrementPointer(n)
+            | BFToken::IncrementValue(n)
+            | BFToken::DecrementValue(n)
+            | BFToken::OutputValue(n)
+            | BFToken::InputValue(n) => n,
+            BFToken::LoopStart | BFToken::LoopEnd | BFToken::DebugDump => 1,
+        };
+        let expand = dialect == Dialect::Extended && counts == Counts::Expand;
+        let count = if expand {
+            expanded_count(command, count, token.span)?
+        } else {
+            count
+        };
+        // `+0` expands to nothing, and the runs around it may join up
+        if count == 0 && expand {
+            continue;
+        }
+        let run = Run {
+            command,
+            text: text.to_string(),
+            count,
+        };
+        if let Some(Item::Run(prev)) = items.last_mut()
+            && let Some(merged) = merge(prev, &run, dialect, counts)
+        {
+            if merged.count == 0 && expand {
+                items.pop();
+            } else {
+                *prev = merged;
+            }
+        } else {
+            if newlines >= 2 {
+                items.push(Item::Blank);
+            }
+            items.push(Item::Run(run));
+        }
+        newlines = 0;
+        code_on_line = true;
+    }
+    Ok(items)
+}
+
+// Joins two runs of the same command split only by whitespace when counts
+// are rewritten anyway. Runs kept as written stay apart, since `+ +` is two
+// tokens and `++` one.
+fn merge(a: &Run, b: &Run, dialect: Dialect, counts: Counts) -> Option<Run> {
+    if a.command != b.command || a.command == '[' || a.command == ']' {
+        return None;
+    }
+    if dialect == Dialect::Standard || counts == Counts::Keep {
+        return None;
+    }
+    let mut count = a.count.checked_add(b.count)?;
+    if counts == Counts::Expand {
+        if matches!(a.command, '+' | '-') {
+            count %= 256;
+        } else if count as u64 > MAX_EXPANDED_RUN {
+            return None;
+        }
+    }
+    Some(Run {
+        command: a.command,
+        text: format!("{}{}", a.text, b.text),
+        count,
+    })
+}
+
+// How many times `command` is written out when its count is expanded. Cells
+// wrap, so `+` and `-` only need the count modulo 256; other runs longer than
+// `MAX_EXPANDED_RUN` are refused.
+fn expanded_count(command: char, count: u32, span: Span) -> Result<u32, Error> {
+    if matches!(command, '+' | '-') {
+        return Ok(count % 256);
+    }
+    if count as u64 > MAX_EXPANDED_RUN {
+        return Err(Error::InvalidOptions(format!(
+            "`{}{}` at line {}, column {} is too long to write out command by command",
+            command, count, span.line, span.column
+        )));
+    }
+    Ok(count)
+}
+
+// Whether `text` can directly follow `line` without changing what either
+// means. Runs of the same command that were not merged need a space between
+// them.
+fn can_join(line: &str, text: &str) -> bool {
+    match (line.chars().last(), text.chars().next()) {
+        (Some(a), Some(b)) => a != b || a == '[' || a == ']',
+        _ => true,
+    }
+}
+
+struct Layout<'a> {
+    options: &'a FormatOptions,
+    counts: Counts,
+    /// None is a blank line
+    lines: Vec<Option<String>>,
+    depth: usize,
+    /// Index of the line holding the last command, for trailing comments
+    code_line: Option<usize>,
+    /// Whether more commands may be packed onto the last line
+    open: bool,
+}
+
+impl Layout<'_> {
+    fn indent(&self) -> String {
+        match self.options.indent_style {
+            IndentStyle::Tabs => "\t".repeat(self.depth),
+            IndentStyle::Spaces => " ".repeat(self.depth * self.options.indent_width),
+        }
+    }
+
+    fn width(&self, line: &str) -> usize {
+        line.chars()
+            .map(|c| {
+                if c == '\t' {
+                    self.options.indent_width
+                } else {
+                    1
+                }
+            })
+            .sum()
+    }
+
+    fn packing(&self) -> bool {
+        self.options.max_width > 0
+    }
+
+    // Whether `text` fits after the last line, or on a new line
+    fn fits(&self, text: &str) -> bool {
+        let len = text.chars().count();
+        match self.lines.last() {
+            Some(Some(line)) if self.open => self.width(line) + len <= self.options.max_width,
+            _ => self.width(&self.indent()) + len <= self.options.max_width,
+        }
+    }
+
+    fn new_line(&mut self, text: &str) {
+        let line = format!("{}{}", self.indent(), text);
+        self.lines.push(Some(line));
+    }
+
+    fn code(&mut self, text: &str) {
+        let joined = match self.lines.last() {
+            Some(Some(line)) if !can_join(line, text) => format!(" {}", text),
+            _ => text.to_string(),
+        };
+        let appends = self.packing() && self.open && self.fits(&joined);
+        match self.lines.last_mut() {
+            Some(Some(line)) if appends => line.push_str(&joined),
+            _ => self.new_line(text),
+        }
+        self.code_line = Some(self.lines.len() - 1);
+        self.open = self.packing();
+    }
+
+    fn blank(&mut self) {
+        if matches!(self.lines.last(), Some(Some(_))) {
+            self.lines.push(None);
+        }
+        self.open = false;
+    }
+
+    fn loop_blank(&mut self) {
+        if self.options.blank_lines == BlankLines::AroundLoops {
+            self.blank();
+        }
+        self.open = false;
+    }
+}
+
+// The loop starting at items[start] written on one line, with the index of
+// its `]`, or None if it holds comments or is longer than `max_width`
+fn inline_loop(
+    items: &[Item],
+    start: usize,
+    counts: Counts,
+    max_width: usize,
+) -> Option<(String, usize)> {
+    let mut text = String::new();
+    let mut depth = 0;
+    for (i, item) in items.iter().enumerate().skip(start) {
+        // Blank lines inside a loop that fits on one line are dropped
+        let run = match item {
+            Item::Run(run) => run,
+            Item::Blank => continue,
+            Item::Comment(..) => return None,
+        };
+        let rendered = run.render(counts);
+        if !can_join(&text, &rendered) {
+            text.push(' ');
+        }
+        text.push_str(&rendered);
+        if text.chars().count() > max_width {
+            return None;
+        }
+        match run.command {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((text, i));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Lays out Brainfuck source with one run of commands per line, indenting
+/// loop bodies with tabs. Comments are kept: a comment after code stays on
+/// that code's line, and a comment on its own line stays on its own line.
+/// Blank lines in the source are kept, at most one in a row. Formatting is
+/// idempotent and leaves the tokens the lexer reads unchanged: separate runs
+/// of a command stay separate.
+pub fn format_code(source: &str) -> Result<String, Error> {
+    format_with(source, &FormatOptions::default(), Dialect::Extended)
+}
+
+/// Splits `source` at the first `!` into the program and the input after it,
+/// where the lexer stops with `with_input_separator`.
+pub fn split_input(source: &str) -> (&str, Option<&str>) {
+    match source.split_once('!') {
+        Some((code, input)) => (code, Some(input)),
+        None => (source, None),
+    }
+}
+
+/// Formats `source` written in `dialect` according to `options`. Comments are
+/// kept as in `format_code`. With `Counts::Keep` the lexer reads the same
+/// tokens as before; rewriting counts joins and splits runs but keeps what
+/// the program does. With `input_separator`, the `!` goes on a line
+/// of its own and the input after it is kept byte for byte. Fails with the
+/// lexer's errors when the source has invalid counts.
+pub fn format_with(
+    source: &str,
+    options: &FormatOptions,
+    dialect: Dialect,
+) -> Result<String, Error> {
+    if options.input_separator
+        && let (code, Some(input)) = split_input(source)
+    {
+        let mut formatted = format_code_with(code, options, dialect)?;
+        if !formatted.is_empty() {
+            formatted.push('\n');
+        }
+        formatted.push('!');
+        formatted.push_str(input);
+        return Ok(formatted);
+    }
+    format_code_with(source, options, dialect)
+}
+
+fn format_code_with(
+    source: &str,
+    options: &FormatOptions,
+    dialect: Dialect,
+) -> Result<String, Error> {
+    let counts = match dialect {
+        Dialect::Standard => Counts::Keep,
+        Dialect::Extended => options.counts,
+    };
+    let items = parse(source, dialect, counts)?;
+    let mut layout = Layout {
+        options,
+        counts,
+        lines: Vec::new(),
+        depth: 0,
+        code_line: None,
+        open: false,
+    };
+    let mut i = 0;
+    while i < items.len() {
+        match &items[i] {
+            Item::Run(run) if run.command == '[' => {
+                if layout.packing()
+                    && let Some((text, end)) =
+                        inline_loop(&items, i, layout.counts, options.max_width)
+                    && layout.fits(&text)
+                {
+                    layout.code(&text);
+                    i = end;
+                } else {
+                    layout.code("[");
+                    layout.depth += 1;
+                    layout.loop_blank();
+                }
+            }
+            Item::Run(run) if run.command == ']' => {
+                layout.loop_blank();
+                layout.depth = layout.depth.saturating_sub(1);
+                layout.code("]");
+            }
+            Item::Run(run) => {
+                layout.code(&run.render(layout.counts));
+            }
+            Item::Comment(text, true) if layout.code_line.is_some() => {
+                if let Some(Some(line)) = layout.code_line.and_then(|i| layout.lines.get_mut(i)) {
+                    line.push(' ');
+                    line.push_str(text);
+                }
+                layout.open = false;
+            }
+            Item::Comment(text, _) => {
+                layout.new_line(text);
+                layout.open = false;
+            }
+            Item::Blank => {
+                if options.blank_lines != BlankLines::None {
+                    layout.blank();
                 }
             }
-            last_type = Some(c);
-        } else if c.is_ascii_digit() {
-            formatted.push(c);
         }
+        i += 1;
     }
-    formatted
+    let mut lines = layout.lines;
+    while let Some(None) = lines.last() {
+        lines.pop();
+    }
+    Ok(lines
+        .into_iter()
+        .map(|line| line.unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+// Comment characters that mean something to some reader of this source: digits
+// are counts in the extended dialect, `#` and `!` are opt-in extensions. Each
+// becomes its fullwidth form, which no Brainfuck implementation reads.
+fn escape_comment(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '0'..='9' | '#' | '!' => char::from_u32(c as u32 + 0xfee0).unwrap_or(c),
+            _ => c,
+        })
+        .collect()
+}
+
+/// Rewrites source in the extended dialect as portable standard Brainfuck,
+/// keeping its layout: every count becomes a run of its command, and digits,
+/// `#` and `!` in comments are escaped so the result means the same to any
+/// interpreter, with or without extensions. Counts on `+` and `-` are reduced
+/// modulo 256, and other counts above `MAX_EXPANDED_RUN` are errors.
+pub fn to_standard(source: &str) -> Result<String, Error> {
+    let tokens = BFLexer::new(source.chars())
+        .with_dialect(Dialect::Extended)
+        .tokenize()?;
+    let mut out = String::with_capacity(source.len());
+    let mut end = 0;
+    for token in &tokens {
+        out.push_str(&escape_comment(&source[end..token.span.offset]));
+        end = token.span.offset + token.span.len;
+        let command = source[token.span.offset..end]
+            .chars()
+            .next()
+            .unwrap_or_default();
+        let count = match token.kind {
+            BFToken::IncrementPointer(n)
+            | BFToken::DecrementPointer(n)
+            | BFToken::IncrementValue(n)
+            | BFToken::DecrementValue(n)
+            | BFToken::OutputValue(n)
+            | BFToken::InputValue(n) => n,
+            BFToken::LoopStart | BFToken::LoopEnd | BFToken::DebugDump => 1,
+        };
+        let count = expanded_count(command, count, token.span)?;
+        out.extend(std::iter::repeat_n(command, count as usize));
+    }
+    out.push_str(&escape_comment(&source[end..]));
+    Ok(out)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lexer::{BFLexer, BFToken};
+    use proptest::prelude::*;
+
+    fn kinds(source: &str, dialect: Dialect) -> Vec<BFToken> {
+        BFLexer::new(source.chars())
+            .with_dialect(dialect)
+            .tokenize()
+            .unwrap()
+            .iter()
+            .map(|t| t.kind)
+            .collect()
+    }
+
+    // Net effect of the commands, so `++` and `+2` compare equal
+    fn effect(source: &str, dialect: Dialect) -> Vec<(char, u64)> {
+        let mut effect: Vec<(char, u64)> = Vec::new();
+        for kind in kinds(source, dialect) {
+            let (c, n) = match kind {
+                BFToken::IncrementPointer(n) => ('>', n),
+                BFToken::DecrementPointer(n) => ('<', n),
+                BFToken::IncrementValue(n) => ('+', n),
+                BFToken::DecrementValue(n) => ('-', n),
+                BFToken::OutputValue(n) => ('.', n),
+                BFToken::InputValue(n) => (',', n),
+                BFToken::LoopStart => ('[', 1),
+                BFToken::LoopEnd => (']', 1),
+                BFToken::DebugDump => ('#', 1),
+            };
+            // Cells wrap, so `+256` does nothing
+            let wrap = |n: u64| if c == '+' || c == '-' { n % 256 } else { n };
+            match effect.last_mut() {
+                Some((last, total)) if *last == c && c != '[' && c != ']' => {
+                    *total = wrap(*total + n as u64);
+                    if *total == 0 {
+                        effect.pop();
+                    }
+                }
+                _ if wrap(n as u64) == 0 => {}
+                _ => effect.push((c, wrap(n as u64))),
+            }
+        }
+        effect
+    }
 
     #[test]
     fn test_basic_formatting() {
         let src = "[>+ -]\n<";
         let expected = "[\n\n\t>\n\t+\n\t-\n\n]\n<";
-        let formatted = format_code(src);
+        let formatted = format_code(src).unwrap();
         assert_eq!(formatted, expected);
     }
 
@@ -82,15 +619,15 @@ mod tests {
     fn test_nested_brackets() {
         let src = "[>[+]<]";
         let expected = "[\n\n\t>\n\t[\n\n\t\t+\n\n\t]\n\t<\n\n]";
-        let formatted = format_code(src);
+        let formatted = format_code(src).unwrap();
         assert_eq!(formatted, expected);
     }
 
     #[test]
-    fn test_ignores_non_commands() {
+    fn test_keeps_comments() {
         let src = "+abc-123";
-        let expected = "+\n-123";
-        let formatted = format_code(src);
+        let expected = "+ abc\n-123";
+        let formatted = format_code(src).unwrap();
         assert_eq!(formatted, expected);
     }
 
@@ -98,7 +635,288 @@ mod tests {
     fn test_tabs_and_newlines() {
         let src = "[+[->]<]";
         let expected = "[\n\n\t+\n\t[\n\n\t\t-\n\t\t>\n\n\t]\n\t<\n\n]";
-        let formatted = format_code(src);
+        let formatted = format_code(src).unwrap();
         assert_eq!(formatted, expected);
     }
+
+    #[test]
+    fn test_comment_placement() {
+        let src = "Header line\n\n\n++ set x\n[ loop\n  move it >+<\n-]";
+        let expected = "Header line\n\n++ set x\n[ loop\n\n\tmove it\n\t>\n\t+\n\t<\n\t-\n\n]";
+        assert_eq!(format_code(src).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_counts_keep_meaning() {
+        let packed = FormatOptions {
+            max_width: 80,
+            ..FormatOptions::default()
+        };
+        for src in [
+            "+ +5",
+            "+\n+5 times",
+            "output value- 3 times.",
+            "-3x+4",
+            "+[-]2",
+            "[+++ +3]",
+        ] {
+            for options in [&FormatOptions::default(), &packed] {
+                for dialect in [Dialect::Standard, Dialect::Extended] {
+                    let formatted = format_with(src, options, dialect).unwrap();
+                    assert_eq!(kinds(&formatted, dialect), kinds(src, dialect), "{}", src);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_test_programs_keep_meaning() {
+        for src in [
+            include_str!("../tests/collatz/prog.b"),
+            include_str!("../tests/numwarp/prog.b"),
+            include_str!("../tests/rot13/prog.b"),
+        ] {
+            let formatted = format_with(src, &FormatOptions::default(), Dialect::Standard).unwrap();
+            assert_eq!(
+                kinds(&formatted, Dialect::Standard),
+                kinds(src, Dialect::Standard)
+            );
+            assert_eq!(
+                format_with(&formatted, &FormatOptions::default(), Dialect::Standard).unwrap(),
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn test_keeps_tokens() {
+        let packed = FormatOptions {
+            max_width: 80,
+            ..FormatOptions::default()
+        };
+        assert_eq!(format_code("+ +").unwrap(), "+\n+");
+        assert_eq!(
+            format_with("+ +\n[- -]", &packed, Dialect::Standard).unwrap(),
+            "+ +[- -]"
+        );
+        assert_eq!(
+            format_with("+ +", &packed, Dialect::Standard).unwrap(),
+            "+ +"
+        );
+    }
+
+    #[test]
+    fn test_packing() {
+        let options = FormatOptions {
+            indent_style: IndentStyle::Spaces,
+            indent_width: 2,
+            max_width: 16,
+            blank_lines: BlankLines::None,
+            ..FormatOptions::default()
+        };
+        let src = "++++\n++[>+++<-]>. [>>>>>>>>>>+<<<<<<<<<<-] done";
+        let expected = "++++ ++[>+++<-]>\n.[\n  >>>>>>>>>>+\n  <<<<<<<<<<-\n] done";
+        assert_eq!(
+            format_with(src, &options, Dialect::Standard).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_counts() {
+        let compress = FormatOptions {
+            counts: Counts::Compress,
+            max_width: 80,
+            ..FormatOptions::default()
+        };
+        let expand = FormatOptions {
+            counts: Counts::Expand,
+            ..compress.clone()
+        };
+        let src = "+++ ++ >5 <3 +0 x";
+        assert_eq!(
+            format_with(src, &compress, Dialect::Extended).unwrap(),
+            "+5>5<3+0 x"
+        );
+        assert_eq!(
+            format_with(src, &expand, Dialect::Extended).unwrap(),
+            "+++++>>>>><<< x"
+        );
+        for options in [&compress, &expand] {
+            let formatted = format_with(src, options, Dialect::Extended).unwrap();
+            assert_eq!(
+                effect(&formatted, Dialect::Extended),
+                effect(src, Dialect::Extended)
+            );
+        }
+        // Counts are left alone in the standard dialect
+        assert_eq!(
+            format_with("+++ +3", &compress, Dialect::Standard).unwrap(),
+            "+++ + 3"
+        );
+    }
+
+    #[test]
+    fn test_input_separator() {
+        let options = FormatOptions {
+            input_separator: true,
+            ..FormatOptions::default()
+        };
+        let src = "+[->+<]!hello, world >< 12\n\n";
+        let formatted = format_with(src, &options, Dialect::Standard).unwrap();
+        assert_eq!(
+            formatted,
+            "+\n[\n\n\t-\n\t>\n\t+\n\t<\n\n]\n!hello, world >< 12\n\n"
+        );
+        assert_eq!(
+            format_with(&formatted, &options, Dialect::Standard).unwrap(),
+            formatted
+        );
+        assert_eq!(
+            format_with("!,.", &options, Dialect::Standard).unwrap(),
+            "!,."
+        );
+        // Without the option `!` is a comment
+        assert_eq!(
+            format_with("+!,.", &FormatOptions::default(), Dialect::Standard).unwrap(),
+            "+ !\n,\n."
+        );
+    }
+
+    #[test]
+    fn test_long_counts() {
+        let expand = FormatOptions {
+            counts: Counts::Expand,
+            max_width: 80,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            format_with("+4000000000 -300 >+256>", &expand, Dialect::Extended).unwrap(),
+            "-------------------------------------------->>"
+        );
+        assert_eq!(to_standard("+4000000000.").unwrap(), ".");
+        assert_eq!(to_standard("-257").unwrap(), "-");
+        for src in [">4000000000", ".2000000"] {
+            assert!(matches!(to_standard(src), Err(Error::InvalidOptions(_))));
+            assert!(format_with(src, &expand, Dialect::Extended).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let toml = "[package]\nname = \"x\"\n\n[format]\n# team style\nindent_style = \"spaces\"\nindent_width = 2\nmax_width = 80\ncounts = \"compress\"\nblank_lines = \"none\"\n";
+        let options = FormatOptions::parse_config(toml, Some("format")).unwrap();
+        assert_eq!(
+            options,
+            FormatOptions {
+                indent_style: IndentStyle::Spaces,
+                indent_width: 2,
+                max_width: 80,
+                counts: Counts::Compress,
+                blank_lines: BlankLines::None,
+                input_separator: false,
+            }
+        );
+        assert_eq!(
+            FormatOptions::parse_config("max_width = 40", None)
+                .unwrap()
+                .max_width,
+            40
+        );
+        assert!(FormatOptions::parse_config("width = 40", None).is_err());
+        assert!(FormatOptions::parse_config("counts = \"some\"", None).is_err());
+    }
+
+    #[test]
+    fn test_invalid_count() {
+        assert!(format_code("+99999999999").is_err());
+        assert!(format_with("+99999999999", &FormatOptions::default(), Dialect::Standard).is_ok());
+    }
+
+    fn options() -> impl Strategy<Value = FormatOptions> {
+        (
+            prop_oneof![Just(IndentStyle::Tabs), Just(IndentStyle::Spaces)],
+            0..5usize,
+            prop_oneof![Just(0usize), 1..40usize],
+            prop_oneof![
+                Just(Counts::Keep),
+                Just(Counts::Compress),
+                Just(Counts::Expand)
+            ],
+            prop_oneof![
+                Just(BlankLines::AroundLoops),
+                Just(BlankLines::Preserve),
+                Just(BlankLines::None)
+            ],
+        )
+            .prop_map(
+                |(indent_style, indent_width, max_width, counts, blank_lines)| FormatOptions {
+                    indent_style,
+                    indent_width,
+                    max_width,
+                    counts,
+                    blank_lines,
+                    input_separator: false,
+                },
+            )
+    }
+
+    // Commands, counts, comments and whitespace in any order, brackets not
+    // necessarily balanced
+    fn program() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            4 => "[-+<>.,\\[\\]]{1,4}",
+            1 => "[0-9]{1,2}",
+            1 => "[a-z#!]{1,5}",
+            2 => prop_oneof![Just(" "), Just("\n"), Just("\t"), Just("\n\n")].prop_map(String::from),
+        ];
+        prop::collection::vec(piece, 0..40).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_idempotent(
+            src in program(),
+            options in options(),
+            extended in any::<bool>(),
+        ) {
+            let dialect = if extended { Dialect::Extended } else { Dialect::Standard };
+            // Keep expanded output small
+            prop_assume!(effect(&src, dialect).iter().all(|&(_, n)| n < 1000));
+            let once = format_with(&src, &options, dialect).unwrap();
+            let twice = format_with(&once, &options, dialect).unwrap();
+            prop_assert_eq!(&twice, &once);
+            prop_assert_eq!(effect(&once, dialect), effect(&src, dialect));
+            if dialect == Dialect::Standard || options.counts == Counts::Keep {
+                prop_assert_eq!(kinds(&once, dialect), kinds(&src, dialect));
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_standard() {
+        let src = "+5 cell #1 = 5!\n[->3+<]>.0\n";
+        let standard = to_standard(src).unwrap();
+        assert_eq!(standard, "+++++ cell ＃１ = ５！\n[->>>+<]>\n");
+        assert_eq!(
+            effect(&standard, Dialect::Standard),
+            effect(src, Dialect::Extended)
+        );
+        // Reading it with the extensions on changes nothing
+        let kinds_with_extensions = |source: &str| {
+            BFLexer::new(source.chars())
+                .with_dialect(Dialect::Extended)
+                .with_debug_dump(true)
+                .with_input_separator(true)
+                .tokenize()
+                .unwrap()
+                .iter()
+                .map(|t| t.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds_with_extensions(&standard),
+            kinds(&standard, Dialect::Standard)
+        );
+    }
 }
@@ -0,0 +1,210 @@
+use crate::lexer::{BFToken, Span, Token};
+use std::fmt;
+
+/// An instruction for the code generator. Unlike tokens, loops know their
+/// labels and cell changes and pointer moves have a direction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Op {
+    /// Add to the current cell; negative values subtract
+    Add(i64),
+    /// Move the pointer; negative values move left
+    Move(i64),
+    Output(u32),
+    Input(u32),
+    LoopStart(u32),
+    LoopEnd(u32),
+    DebugDump,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Instr {
+    pub op: Op,
+    /// Where the instruction came from, the first token of a folded run
+    pub span: Span,
+}
+
+/// Assigns loop labels while lowering tokens one at a time.
+#[derive(Debug, Default)]
+pub struct Lowering {
+    /// Loops that are still open, with where they started
+    pub open: Vec<(u32, Span)>,
+    next_id: u32,
+}
+
+impl Lowering {
+    /// The instruction for `token`, or None for a `]` without a `[`.
+    pub fn lower(&mut self, token: &Token) -> Option<Instr> {
+        let op = match token.kind {
+            BFToken::IncrementPointer(n) => Op::Move(n as i64),
+            BFToken::DecrementPointer(n) => Op::Move(-(n as i64)),
+            BFToken::IncrementValue(n) => Op::Add(n as i64),
+            BFToken::DecrementValue(n) => Op::Add(-(n as i64)),
+            BFToken::OutputValue(n) => Op::Output(n),
+            BFToken::InputValue(n) => Op::Input(n),
+            BFToken::LoopStart => {
+                let id = self.next_id;
+                self.open.push((id, token.span));
+                self.next_id += 1;
+                Op::LoopStart(id)
+            }
+            BFToken::LoopEnd => Op::LoopEnd(self.open.pop()?.0),
+            BFToken::DebugDump => Op::DebugDump,
+        };
+        Some(Instr {
+            op,
+            span: token.span,
+        })
+    }
+}
+
+/// Lowers tokens to instructions one for one, skipping unmatched `]`.
+pub fn lower(tokens: &[Token]) -> Vec<Instr> {
+    let mut lowering = Lowering::default();
+    tokens.iter().filter_map(|t| lowering.lower(t)).collect()
+}
+
+// Cells wrap, so adding 200 is subtracting 56
+fn wrap(n: i64) -> i64 {
+    (n + 127).rem_euclid(256) - 127
+}
+
+/// Folds runs of cell changes, pointer moves, output and input separated only
+/// by comments into single instructions, cancelling `+-` and `<>`, and drops
+/// instructions that do nothing. With `bounds_check` every pointer move is
+/// kept, so out of range moves are reported where they happen.
+pub fn optimize(ir: &[Instr], bounds_check: bool) -> Vec<Instr> {
+    let mut out: Vec<Instr> = Vec::with_capacity(ir.len());
+    for instr in ir {
+        let mut instr = *instr;
+        if let Op::Add(n) = instr.op {
+            instr.op = Op::Add(wrap(n));
+        }
+        if let Some(last) = out.last_mut() {
+            let joined = match (last.op, instr.op) {
+                (Op::Add(a), Op::Add(b)) => Some(Op::Add(wrap(a + b))),
+                (Op::Move(a), Op::Move(b)) if !bounds_check => Some(Op::Move(a + b)),
+                (Op::Output(a), Op::Output(b)) => a.checked_add(b).map(Op::Output),
+                (Op::Input(a), Op::Input(b)) => a.checked_add(b).map(Op::Input),
+                _ => None,
+            };
+            if let Some(op) = joined {
+                last.op = op;
+                if is_no_op(op) {
+                    out.pop();
+                }
+                continue;
+            }
+        }
+        if !is_no_op(instr.op) {
+            out.push(instr);
+        }
+    }
+    out
+}
+
+fn is_no_op(op: Op) -> bool {
+    matches!(op, Op::Add(0) | Op::Move(0) | Op::Output(0) | Op::Input(0))
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Add(n) => write!(f, "add {}", n),
+            Op::Move(n) => write!(f, "move {}", n),
+            Op::Output(n) => write!(f, "output {}", n),
+            Op::Input(n) => write!(f, "input {}", n),
+            Op::LoopStart(id) => write!(f, "loop {}", id),
+            Op::LoopEnd(id) => write!(f, "end {}", id),
+            Op::DebugDump => write!(f, "dump"),
+        }
+    }
+}
+
+/// One instruction per line with its source position, indented by loop
+/// depth.
+pub fn to_text(ir: &[Instr]) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for instr in ir {
+        if let Op::LoopEnd(_) = instr.op {
+            depth -= 1;
+        }
+        out.push_str(&format!(
+            "{}:{}\t{}{}\n",
+            instr.span.line,
+            instr.span.column,
+            "  ".repeat(depth),
+            instr.op
+        ));
+        if let Op::LoopStart(_) = instr.op {
+            depth += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{BFLexer, Dialect};
+
+    fn ops(source: &str, optimized: bool, bounds_check: bool) -> Vec<Op> {
+        let tokens = BFLexer::new(source.chars())
+            .with_dialect(Dialect::Extended)
+            .tokenize()
+            .unwrap();
+        let mut ir = lower(&tokens);
+        if optimized {
+            ir = optimize(&ir, bounds_check);
+        }
+        ir.iter().map(|i| i.op).collect()
+    }
+
+    #[test]
+    fn test_lower() {
+        assert_eq!(
+            ops("+[->[<]].", false, false),
+            vec![
+                Op::Add(1),
+                Op::LoopStart(0),
+                Op::Add(-1),
+                Op::Move(1),
+                Op::LoopStart(1),
+                Op::Move(-1),
+                Op::LoopEnd(1),
+                Op::LoopEnd(0),
+                Op::Output(1),
+            ]
+        );
+        assert_eq!(ops("]+", false, false), vec![Op::Add(1)]);
+    }
+
+    #[test]
+    fn test_optimize() {
+        assert_eq!(
+            ops("+ + - >0 .", true, false),
+            vec![Op::Add(1), Op::Output(1)]
+        );
+        assert_eq!(ops("+300 -2", true, false), vec![Op::Add(42)]);
+        assert_eq!(ops("+250", true, false), vec![Op::Add(-6)]);
+        assert_eq!(ops("> >> <<< .", true, false), vec![Op::Output(1)]);
+        assert_eq!(
+            ops("> >> <<< .", true, true),
+            vec![Op::Move(1), Op::Move(2), Op::Move(-3), Op::Output(1)]
+        );
+        // Loops are barriers
+        assert_eq!(
+            ops("+[]-", true, false),
+            vec![Op::Add(1), Op::LoopStart(0), Op::LoopEnd(0), Op::Add(-1)]
+        );
+    }
+
+    #[test]
+    fn test_to_text() {
+        let tokens = BFLexer::new("+[\n->]".chars()).tokenize().unwrap();
+        assert_eq!(
+            to_text(&lower(&tokens)),
+            "1:1\tadd 1\n1:2\tloop 0\n2:1\t  add -1\n2:2\t  move 1\n2:3\tend 0\n"
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use crate::diagnostic::Diagnostic;
+use crate::error::Error;
+use std::io::{self, BufReader, Read};
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum BFToken {
     IncrementPointer(u32),
@@ -8,14 +12,148 @@ pub enum BFToken {
     InputValue(u32),
     LoopStart,
     LoopEnd,
+    /// `#`, only produced when the debug extension is enabled
+    DebugDump,
+}
+
+/// Location of a token in the source. `line` and `column` are 1-based,
+/// `column` counts chars; `offset` and `len` are in bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Token {
+    pub kind: BFToken,
+    pub span: Span,
+}
+
+// Tokens built by hand have no source location
+impl From<BFToken> for Token {
+    fn from(kind: BFToken) -> Self {
+        Token {
+            kind,
+            span: Span::default(),
+        }
+    }
+}
+
+/// Which flavour of Brainfuck the source is written in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Dialect {
+    /// The eight commands; every other character, digits included, is a comment
+    #[default]
+    Standard,
+    /// Digits right after a command repeat it, e.g. `+5` is `+++++`
+    Extended,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "standard" => Some(Dialect::Standard),
+            "extended" => Some(Dialect::Extended),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes UTF-8 from a reader one char at a time, so the lexer can run over
+/// sources without holding them in memory. Invalid bytes become U+FFFD, but
+/// spans still count the bytes actually read. A read error ends the input and
+/// is kept for `BFLexer::take_read_error`.
+pub struct ReadChars<R: Read> {
+    bytes: io::Bytes<BufReader<R>>,
+    pending: Option<u8>,
+    error: Option<io::Error>,
+    /// Bytes taken up by the last char, which for U+FFFD may be fewer than
+    /// its own encoding
+    last_len: usize,
+}
+
+impl<R: Read> ReadChars<R> {
+    pub fn new(reader: R) -> Self {
+        ReadChars {
+            bytes: BufReader::new(reader).bytes(),
+            pending: None,
+            error: None,
+            last_len: 0,
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        if let Some(b) = self.pending.take() {
+            return Some(b);
+        }
+        match self.bytes.next()? {
+            Ok(b) => Some(b),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for ReadChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        let first = self.next_byte()?;
+        self.last_len = 1;
+        let width = match first {
+            0x00..=0x7f => return Some(first as char),
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+        let mut buf = [first, 0, 0, 0];
+        for slot in buf.iter_mut().take(width).skip(1) {
+            match self.next_byte() {
+                Some(b @ 0x80..=0xbf) => {
+                    *slot = b;
+                    self.last_len += 1;
+                }
+                // Not a continuation byte, so it starts the next char
+                Some(b) => {
+                    self.pending = Some(b);
+                    return Some(char::REPLACEMENT_CHARACTER);
+                }
+                None => return Some(char::REPLACEMENT_CHARACTER),
+            }
+        }
+        let c = std::str::from_utf8(&buf[..width])
+            .ok()
+            .and_then(|s| s.chars().next());
+        Some(c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
 }
 
 pub struct BFLexer<I>
 where
     I: Iterator<Item = char>,
 {
-    pos: usize,
-    input: std::iter::Peekable<I>,
+    offset: usize,
+    line: usize,
+    column: usize,
+    input: I,
+    /// Source bytes taken up by a char just read from `input`
+    byte_len: fn(&I, char) -> usize,
+    peeked: Option<char>,
+    dialect: Dialect,
+    strict_numbers: bool,
+    debug_dump: bool,
+    input_separator: bool,
+    embedded_input: Option<String>,
+    warnings: Vec<Diagnostic>,
 }
 
 impl<I> BFLexer<I>
@@ -24,52 +162,159 @@ where
 {
     pub fn new(input: I) -> Self {
         BFLexer {
-            pos: 0,
-            input: input.peekable(),
+            offset: 0,
+            line: 1,
+            column: 1,
+            input,
+            byte_len: |_, c| c.len_utf8(),
+            peeked: None,
+            dialect: Dialect::default(),
+            strict_numbers: false,
+            debug_dump: false,
+            input_separator: false,
+            embedded_input: None,
+            warnings: Vec::new(),
         }
     }
 
-    pub fn next_token(&mut self) -> Option<BFToken> {
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Read `#` as a command that dumps the tape instead of a comment.
+    pub fn with_debug_dump(mut self, debug_dump: bool) -> Self {
+        self.debug_dump = debug_dump;
+        self
+    }
+
+    /// Stop at the first `!` and keep the rest of the source as the
+    /// program's input, see `embedded_input`.
+    pub fn with_input_separator(mut self, input_separator: bool) -> Self {
+        self.input_separator = input_separator;
+        self
+    }
+
+    /// Everything after the `!` separator, once the lexer has reached it.
+    pub fn embedded_input(&self) -> Option<&str> {
+        self.embedded_input.as_deref()
+    }
+
+    /// Whether `!` ends the program, see `with_input_separator`.
+    pub fn input_separator(&self) -> bool {
+        self.input_separator
+    }
+
+    /// Reject digits after a command instead of reading them as a count or
+    /// skipping them as a comment.
+    pub fn with_strict_numbers(mut self, strict_numbers: bool) -> Self {
+        self.strict_numbers = strict_numbers;
+        self
+    }
+
+    /// Warnings collected so far, e.g. for ambiguous counts like `+++5`.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        if self.peeked.is_none() {
+            self.peeked = self.input.next();
+        }
+        self.peeked
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peeked.take().or_else(|| self.input.next())?;
+        self.offset += (self.byte_len)(&self.input, c);
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    pub fn next_token(&mut self) -> Option<Result<Token, Error>> {
         use BFToken::*;
-        while let Some(&c) = self.input.peek() {
-            match c {
+        while let Some(c) = self.peek() {
+            let start = Span {
+                offset: self.offset,
+                line: self.line,
+                column: self.column,
+                len: 0,
+            };
+            let kind = match c {
                 '>' | '<' | '+' | '-' | '.' | ',' => {
-                    self.input.next();
-                    self.pos += 1;
+                    self.bump();
                     let mut count = 1;
                     // Combine repeated chars
-                    while let Some(&next) = self.input.peek() {
+                    while let Some(next) = self.peek() {
                         if next == c {
-                            self.input.next();
-                            self.pos += 1;
+                            self.bump();
                             count += 1;
                         } else {
                             break;
                         }
                     }
-                    // Read number after char
+                    // Read number after char. In the standard dialect digits
+                    // are comments and are skipped like any other char.
                     let mut num_str = String::new();
-                    while let Some(&next) = self.input.peek() {
+                    let reads_number = self.dialect == Dialect::Extended || self.strict_numbers;
+                    while reads_number && let Some(next) = self.peek() {
                         if next.is_ascii_digit() {
                             num_str.push(next);
-                            self.input.next();
-                            self.pos += 1;
+                            self.bump();
                         } else {
                             break;
                         }
                     }
-                    let num = if !num_str.is_empty() {
+                    let span = Span {
+                        len: self.offset - start.offset,
+                        ..start
+                    };
+                    let text = || format!("{}{}", c.to_string().repeat(count as usize), num_str);
+                    let num = if num_str.is_empty() {
+                        count
+                    } else if self.strict_numbers {
+                        return Some(Err(Error::Syntax(vec![
+                            Diagnostic::error(format!("number after `{}`", c), span)
+                                .with_label("counts are not allowed with --strict-numbers"),
+                        ])));
+                    } else {
                         match num_str.parse::<u32>() {
-                            Ok(n) => n,
+                            Ok(n) => {
+                                if count > 1 {
+                                    self.warnings.push(
+                                        Diagnostic::warning(
+                                            format!("`{}` means `{}{}`", text(), c, n),
+                                            span,
+                                        )
+                                        .with_label(format!(
+                                            "the count replaces the run of {} `{}`, it is not added to it",
+                                            count, c
+                                        )),
+                                    );
+                                }
+                                n
+                            }
                             Err(e) => {
-                                eprintln!("Error parsing number after '{}': {}", c, e);
-                                count // fallback to count
+                                return Some(Err(Error::Syntax(vec![
+                                    Diagnostic::error(
+                                        format!("invalid count in `{}`", text()),
+                                        span,
+                                    )
+                                    .with_label(format!(
+                                        "{}, the largest count is {}",
+                                        e,
+                                        u32::MAX
+                                    )),
+                                ])));
                             }
                         }
-                    } else {
-                        count
                     };
-                    return Some(match c {
+                    match c {
                         '>' => IncrementPointer(num),
                         '<' => DecrementPointer(num),
                         '+' => IncrementValue(num),
@@ -77,53 +322,130 @@ where
                         '.' => OutputValue(num),
                         ',' => InputValue(num),
                         _ => unreachable!(),
-                    });
+                    }
                 }
                 '[' => {
-                    self.input.next();
-                    self.pos += 1;
-                    return Some(LoopStart);
+                    self.bump();
+                    LoopStart
                 }
                 ']' => {
-                    self.input.next();
-                    self.pos += 1;
-                    return Some(LoopEnd);
+                    self.bump();
+                    LoopEnd
+                }
+                '!' if self.input_separator => {
+                    self.bump();
+                    let rest = self.peeked.take().into_iter().chain(self.input.by_ref());
+                    self.embedded_input = Some(rest.collect());
+                    return None;
+                }
+                '#' if self.debug_dump => {
+                    self.bump();
+                    DebugDump
                 }
                 _ => {
-                    self.input.next();
-                    self.pos += 1;
+                    self.bump();
                     continue;
                 } // skip non-command chars
-            }
+            };
+            return Some(Ok(Token {
+                kind,
+                span: Span {
+                    len: self.offset - start.offset,
+                    ..start
+                },
+            }));
         }
         None
     }
 
-    pub fn tokenize(&mut self) -> Vec<BFToken> {
+    /// Lexes the whole input, reporting every invalid token at once.
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, Error> {
         let mut tokens = Vec::new();
+        let mut errors = Vec::new();
         while let Some(token) = self.next_token() {
-            tokens.push(token);
+            match token {
+                Ok(token) => tokens.push(token),
+                Err(Error::Syntax(diagnostics)) => errors.extend(diagnostics),
+                Err(e) => return Err(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(Error::Syntax(errors))
         }
-        tokens
     }
 
-    pub fn check_syntax(tokens: &[BFToken]) -> Result<(), String> {
+    /// Checks that brackets balance, reporting every unmatched one.
+    pub fn check_syntax(tokens: &[Token]) -> Result<(), Error> {
         let mut stack = Vec::new();
-        for (i, token) in tokens.iter().enumerate() {
-            match token {
-                BFToken::LoopStart => stack.push(i),
-                BFToken::LoopEnd => {
-                    if stack.pop().is_none() {
-                        return Err(format!("Unmatched LoopEnd (]) at token {}", i));
-                    }
-                }
+        let mut unmatched_ends = Vec::new();
+        for token in tokens {
+            match token.kind {
+                BFToken::LoopStart => stack.push(token.span),
+                BFToken::LoopEnd if stack.pop().is_none() => unmatched_ends.push(token.span),
                 _ => {}
             }
         }
-        if let Some(pos) = stack.pop() {
-            return Err(format!("Unmatched LoopStart ([) at token {}", pos));
+        let unmatched_starts = stack;
+        let nearest = |span: &Span, candidates: &[Span]| {
+            candidates
+                .iter()
+                .min_by_key(|c| c.offset.abs_diff(span.offset))
+                .copied()
+        };
+
+        let mut diagnostics = Vec::new();
+        for span in &unmatched_starts {
+            let mut diagnostic =
+                Diagnostic::error("unmatched `[`", *span).with_label("this `[` was never closed");
+            if let Some(candidate) = nearest(span, &unmatched_ends) {
+                diagnostic =
+                    diagnostic.with_note("the nearest unmatched `]` is here", Some(candidate));
+            }
+            diagnostics.push(diagnostic);
+        }
+        for span in &unmatched_ends {
+            let mut diagnostic =
+                Diagnostic::error("unmatched `]`", *span).with_label("this `]` has no opening `[`");
+            if let Some(candidate) = nearest(span, &unmatched_starts) {
+                diagnostic =
+                    diagnostic.with_note("the nearest unmatched `[` is here", Some(candidate));
+            }
+            diagnostics.push(diagnostic);
         }
-        Ok(())
+        if diagnostics.is_empty() {
+            return Ok(());
+        }
+        diagnostics.sort_by_key(|d| d.span.offset);
+        Err(Error::Syntax(diagnostics))
+    }
+}
+
+impl<R: Read> BFLexer<ReadChars<R>> {
+    /// Lexes straight from a reader, e.g. a file too big to read into memory.
+    pub fn from_reader(reader: R) -> Self {
+        BFLexer {
+            byte_len: |input, _| input.last_len,
+            ..BFLexer::new(ReadChars::new(reader))
+        }
+    }
+
+    /// The error that ended the input early, if reading failed.
+    pub fn take_read_error(&mut self) -> Option<io::Error> {
+        self.input.error.take()
+    }
+}
+
+/// Yields tokens one at a time, see `next_token`.
+impl<I> Iterator for BFLexer<I>
+where
+    I: Iterator<Item = char>,
+{
+    type Item = Result<Token, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token()
     }
 }
 
@@ -132,8 +454,12 @@ mod tests {
     use super::*;
 
     fn lex(input: &str) -> Vec<BFToken> {
-        let mut lexer = BFLexer::new(input.chars());
-        lexer.tokenize()
+        lex_spanned(input).into_iter().map(|t| t.kind).collect()
+    }
+
+    fn lex_spanned(input: &str) -> Vec<Token> {
+        let mut lexer = BFLexer::new(input.chars()).with_dialect(Dialect::Extended);
+        lexer.tokenize().unwrap()
     }
 
     #[test]
@@ -193,15 +519,216 @@ mod tests {
 
     #[test]
     fn test_check_syntax() {
-        let tokens = lex("[--+.]");
+        let tokens = lex_spanned("[--+.]");
         assert!(BFLexer::<std::str::Chars>::check_syntax(&tokens).is_ok());
-        let tokens = lex("++[-->+++<]");
+        let tokens = lex_spanned("++[-->+++<]");
         assert!(BFLexer::<std::str::Chars>::check_syntax(&tokens).is_ok());
-        let tokens = lex("[");
+        let tokens = lex_spanned("[");
         assert!(BFLexer::<std::str::Chars>::check_syntax(&tokens).is_err());
-        let tokens = lex("]");
+        let tokens = lex_spanned("]");
         assert!(BFLexer::<std::str::Chars>::check_syntax(&tokens).is_err());
-        let tokens = lex("[[[]]");
+        let tokens = lex_spanned("[[[]]");
         assert!(BFLexer::<std::str::Chars>::check_syntax(&tokens).is_err());
     }
+
+    #[test]
+    fn test_spans() {
+        let tokens = lex_spanned("+++ comment\n  >12 é[");
+        assert_eq!(
+            tokens[0].span,
+            Span {
+                offset: 0,
+                line: 1,
+                column: 1,
+                len: 3
+            }
+        );
+        assert_eq!(
+            tokens[1].span,
+            Span {
+                offset: 14,
+                line: 2,
+                column: 3,
+                len: 3
+            }
+        );
+        // é is two bytes but one column
+        assert_eq!(
+            tokens[2].span,
+            Span {
+                offset: 20,
+                line: 2,
+                column: 8,
+                len: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_syntax_location() {
+        let tokens = lex_spanned("+\n  [-\n]]");
+        assert_eq!(
+            BFLexer::<std::str::Chars>::check_syntax(&tokens)
+                .unwrap_err()
+                .to_string(),
+            "unmatched `]` at line 3, column 2"
+        );
+        let tokens = lex_spanned("+[\n[-]");
+        assert_eq!(
+            BFLexer::<std::str::Chars>::check_syntax(&tokens)
+                .unwrap_err()
+                .to_string(),
+            "unmatched `[` at line 1, column 2"
+        );
+    }
+
+    #[test]
+    fn test_check_syntax_reports_all() {
+        let tokens = lex_spanned("]+[[-]\n]]");
+        match BFLexer::<std::str::Chars>::check_syntax(&tokens) {
+            Err(Error::Syntax(diagnostics)) => {
+                let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                assert_eq!(
+                    messages,
+                    vec![
+                        "unmatched `]` at line 1, column 1",
+                        "unmatched `]` at line 2, column 2",
+                    ]
+                );
+                assert!(diagnostics[0].notes.is_empty());
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let tokens = lex_spanned("[+\n]]");
+        match BFLexer::<std::str::Chars>::check_syntax(&tokens) {
+            Err(Error::Syntax(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!(diagnostics[0].span.line, 2);
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let tokens = lex_spanned("[[+]\n-]]");
+        match BFLexer::<std::str::Chars>::check_syntax(&tokens) {
+            Err(Error::Syntax(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!(diagnostics[0].message, "unmatched `]`");
+                assert_eq!(diagnostics[0].span.column, 3);
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check_syntax_notes_candidate() {
+        let tokens = lex_spanned("]+[");
+        match BFLexer::<std::str::Chars>::check_syntax(&tokens) {
+            Err(Error::Syntax(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 2);
+                assert_eq!(diagnostics[0].notes[0].span.unwrap().column, 3);
+                assert_eq!(diagnostics[1].notes[0].span.unwrap().column, 1);
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_count_warning() {
+        let mut lexer = BFLexer::new("+5 ++5 --".chars()).with_dialect(Dialect::Extended);
+        let tokens = lexer.tokenize().unwrap();
+        assert_eq!(tokens[1].kind, BFToken::IncrementValue(5));
+        assert_eq!(lexer.warnings().len(), 1);
+        assert_eq!(lexer.warnings()[0].message, "`++5` means `+5`");
+        assert_eq!(lexer.warnings()[0].span.column, 4);
+    }
+
+    #[test]
+    fn test_strict_numbers() {
+        let mut lexer = BFLexer::new("+-3 x 4 .12".chars()).with_strict_numbers(true);
+        match lexer.tokenize() {
+            Err(Error::Syntax(diagnostics)) => {
+                let columns: Vec<usize> = diagnostics.iter().map(|d| d.span.column).collect();
+                assert_eq!(columns, vec![2, 9]);
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+        let mut lexer = BFLexer::new("+- x 4".chars()).with_strict_numbers(true);
+        assert!(lexer.tokenize().is_ok());
+    }
+
+    #[test]
+    fn test_standard_dialect() {
+        let mut lexer = BFLexer::new("output value- 3 times.\n+12".chars());
+        let tokens: Vec<BFToken> = lexer.tokenize().unwrap().iter().map(|t| t.kind).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                BFToken::DecrementValue(1),
+                BFToken::OutputValue(1),
+                BFToken::IncrementValue(1),
+            ]
+        );
+        let mut lexer = BFLexer::new("-3".chars()).with_strict_numbers(true);
+        assert!(lexer.tokenize().is_err());
+        assert_eq!(Dialect::from_name("extended"), Some(Dialect::Extended));
+        assert_eq!(Dialect::from_name("nope"), None);
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        assert_eq!(lex("+#"), vec![BFToken::IncrementValue(1)]);
+        let mut lexer = BFLexer::new("+#.".chars()).with_debug_dump(true);
+        let tokens: Vec<BFToken> = lexer.tokenize().unwrap().iter().map(|t| t.kind).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                BFToken::IncrementValue(1),
+                BFToken::DebugDump,
+                BFToken::OutputValue(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_input_separator() {
+        assert_eq!(lex("+!-").len(), 2);
+        let mut lexer = BFLexer::new(",[.,]!ab!+\n".chars()).with_input_separator(true);
+        assert_eq!(lexer.tokenize().unwrap().len(), 5);
+        assert_eq!(lexer.embedded_input(), Some("ab!+\n"));
+        let mut lexer = BFLexer::new(",.".chars()).with_input_separator(true);
+        lexer.tokenize().unwrap();
+        assert_eq!(lexer.embedded_input(), None);
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let source = "+é>\n[-]";
+        let mut lexer = BFLexer::from_reader(source.as_bytes());
+        let tokens = lexer.tokenize().unwrap();
+        assert_eq!(tokens, lex_spanned(source));
+        assert!(lexer.take_read_error().is_none());
+        // Invalid UTF-8 doesn't hide the commands around it
+        let chars: String = ReadChars::new(&b"+\xff\xe2\x82-"[..]).collect();
+        assert_eq!(chars, "+\u{fffd}\u{fffd}-");
+        // Spans after it still point at the right bytes
+        let mut lexer = BFLexer::from_reader(&b"+\xff\xe2\x82-5"[..]).with_strict_numbers(true);
+        match lexer.tokenize() {
+            Err(Error::Syntax(diagnostics)) => {
+                let span = diagnostics[0].span;
+                assert_eq!((span.offset, span.column), (4, 4));
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        let mut lexer = BFLexer::new("+\n>99999999999".chars()).with_dialect(Dialect::Extended);
+        match lexer.tokenize() {
+            Err(Error::Syntax(diagnostics)) => {
+                let span = diagnostics[0].span;
+                assert_eq!((span.line, span.column, span.len), (2, 1, 12))
+            }
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
 }
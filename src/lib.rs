@@ -1,4 +1,13 @@
+pub mod analysis;
 pub mod asm;
 pub mod compiler;
+pub mod diagnostic;
+pub mod diff;
+pub mod error;
 pub mod format;
+pub mod ir;
 pub mod lexer;
+pub mod lint;
+pub mod minify;
+pub mod tempdir;
+pub mod toolchain;
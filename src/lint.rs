@@ -0,0 +1,362 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{BFToken, Span, Token};
+
+/// Static checks for code that is legal but almost certainly not what the
+/// author meant.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Lint {
+    /// `+0`, `>0` and friends
+    NoOp,
+    /// Runs of `+` and `-`, or `>` and `<`, that add up to nothing
+    Cancelling,
+    /// Loops that can never reach zero once entered, e.g. `[]` or `[+>-<]`
+    InfiniteLoop,
+    /// Loops right after a `]`, where the current cell is always zero
+    DeadLoop,
+    /// Pointer moving left of the first cell before any loop
+    NegativePointer,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::NoOp,
+        Lint::Cancelling,
+        Lint::InfiniteLoop,
+        Lint::DeadLoop,
+        Lint::NegativePointer,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::NoOp => "no-op",
+            Lint::Cancelling => "cancelling",
+            Lint::InfiniteLoop => "infinite-loop",
+            Lint::DeadLoop => "dead-loop",
+            Lint::NegativePointer => "negative-pointer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+/// Which lints run. All of them are enabled by default.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LintConfig {
+    pub enabled: Vec<Lint>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig {
+            enabled: Lint::ALL.to_vec(),
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn allow(mut self, lint: Lint) -> Self {
+        self.enabled.retain(|l| *l != lint);
+        self
+    }
+
+    pub fn is_enabled(&self, lint: Lint) -> bool {
+        self.enabled.contains(&lint)
+    }
+}
+
+fn symbol(kind: BFToken) -> char {
+    match kind {
+        BFToken::IncrementPointer(_) => '>',
+        BFToken::DecrementPointer(_) => '<',
+        BFToken::IncrementValue(_) => '+',
+        BFToken::DecrementValue(_) => '-',
+        BFToken::OutputValue(_) => '.',
+        BFToken::InputValue(_) => ',',
+        BFToken::LoopStart => '[',
+        BFToken::LoopEnd => ']',
+        BFToken::DebugDump => '#',
+    }
+}
+
+// Span from the start of `a` to the end of `b`
+fn join(a: Span, b: Span) -> Span {
+    Span {
+        len: b.offset + b.len - a.offset,
+        ..a
+    }
+}
+
+fn warn(lint: Lint, message: String, span: Span, label: &str) -> Diagnostic {
+    Diagnostic::warning(message, span)
+        .with_label(label)
+        .with_note(
+            format!(
+                "`{}` is on by default, silence it with --allow {}",
+                lint.name(),
+                lint.name()
+            ),
+            None,
+        )
+}
+
+/// Runs every enabled lint. `tokens` must have balanced brackets.
+pub fn lint(tokens: &[Token], config: &LintConfig) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    if config.is_enabled(Lint::NoOp) {
+        no_op(tokens, &mut diagnostics);
+    }
+    if config.is_enabled(Lint::Cancelling) {
+        cancelling(tokens, &mut diagnostics);
+    }
+    if config.is_enabled(Lint::InfiniteLoop) {
+        infinite_loop(tokens, &mut diagnostics);
+    }
+    if config.is_enabled(Lint::DeadLoop) {
+        dead_loop(tokens, &mut diagnostics);
+    }
+    if config.is_enabled(Lint::NegativePointer) {
+        negative_pointer(tokens, &mut diagnostics);
+    }
+    diagnostics.sort_by_key(|d| d.span.offset);
+    diagnostics
+}
+
+fn no_op(tokens: &[Token], diagnostics: &mut Vec<Diagnostic>) {
+    for token in tokens {
+        if let BFToken::IncrementPointer(0)
+        | BFToken::DecrementPointer(0)
+        | BFToken::IncrementValue(0)
+        | BFToken::DecrementValue(0)
+        | BFToken::OutputValue(0)
+        | BFToken::InputValue(0) = token.kind
+        {
+            let c = symbol(token.kind);
+            diagnostics.push(warn(
+                Lint::NoOp,
+                format!("`{}0` does nothing", c),
+                token.span,
+                "repeated zero times",
+            ));
+        }
+    }
+}
+
+// Net change of a `+`/`-` token, or move of a `>`/`<` token, with which of the
+// two kinds of run it belongs to
+fn change(kind: BFToken) -> Option<(bool, i64)> {
+    match kind {
+        BFToken::IncrementValue(n) => Some((true, n as i64)),
+        BFToken::DecrementValue(n) => Some((true, -(n as i64))),
+        BFToken::IncrementPointer(n) => Some((false, n as i64)),
+        BFToken::DecrementPointer(n) => Some((false, -(n as i64))),
+        _ => None,
+    }
+}
+
+// Each maximal run of `+` and `-`, or of `>` and `<`, that goes both ways and
+// nets to zero. Cells wrap, so `+` and `-` are summed modulo 256.
+fn cancelling(tokens: &[Token], diagnostics: &mut Vec<Diagnostic>) {
+    let mut start = 0;
+    while start < tokens.len() {
+        let Some((value, _)) = change(tokens[start].kind) else {
+            start += 1;
+            continue;
+        };
+        let mut end = start;
+        let mut net: i64 = 0;
+        let (mut up, mut down) = (false, false);
+        while let Some(token) = tokens.get(end)
+            && let Some((same, n)) = change(token.kind)
+            && same == value
+        {
+            net += n;
+            up |= n > 0;
+            down |= n < 0;
+            end += 1;
+        }
+        let net = if value { net.rem_euclid(256) } else { net };
+        if up && down && net == 0 {
+            let (a, b) = if value { ('+', '-') } else { ('>', '<') };
+            diagnostics.push(warn(
+                Lint::Cancelling,
+                format!("these `{}` and `{}` cancel out", a, b),
+                join(tokens[start].span, tokens[end - 1].span),
+                "together these do nothing",
+            ));
+        }
+        start = end;
+    }
+}
+
+// A loop without nested loops or input, whose body returns the pointer to
+// where it started and leaves that cell unchanged, spins forever once entered.
+fn infinite_loop(tokens: &[Token], diagnostics: &mut Vec<Diagnostic>) {
+    let mut start = None;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            BFToken::LoopStart => start = Some(i),
+            BFToken::LoopEnd => {
+                if let Some(s) = start.take() {
+                    let body = &tokens[s + 1..i];
+                    if never_terminates(body) {
+                        diagnostics.push(warn(
+                            Lint::InfiniteLoop,
+                            "this loop never terminates once entered".to_string(),
+                            join(tokens[s].span, token.span),
+                            "the current cell is the same at the end of every iteration",
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn never_terminates(body: &[Token]) -> bool {
+    let mut offset: i64 = 0;
+    let mut delta: i64 = 0;
+    for token in body {
+        match token.kind {
+            BFToken::IncrementPointer(n) => offset += n as i64,
+            BFToken::DecrementPointer(n) => offset -= n as i64,
+            BFToken::IncrementValue(n) if offset == 0 => delta += n as i64,
+            BFToken::DecrementValue(n) if offset == 0 => delta -= n as i64,
+            BFToken::IncrementValue(_)
+            | BFToken::DecrementValue(_)
+            | BFToken::OutputValue(_)
+            | BFToken::DebugDump => {}
+            BFToken::InputValue(_) | BFToken::LoopStart | BFToken::LoopEnd => return false,
+        }
+    }
+    offset == 0 && delta.rem_euclid(256) == 0
+}
+
+fn dead_loop(tokens: &[Token], diagnostics: &mut Vec<Diagnostic>) {
+    for pair in tokens.windows(2) {
+        if pair[0].kind == BFToken::LoopEnd && pair[1].kind == BFToken::LoopStart {
+            diagnostics.push(
+                warn(
+                    Lint::DeadLoop,
+                    "this loop is never entered".to_string(),
+                    pair[1].span,
+                    "the current cell is always zero here",
+                )
+                .with_note(
+                    "the previous loop only exits on a zero cell",
+                    Some(pair[0].span),
+                ),
+            );
+        }
+    }
+}
+
+// Only the straight-line code before the first loop is certain to run in order
+fn negative_pointer(tokens: &[Token], diagnostics: &mut Vec<Diagnostic>) {
+    let mut offset: i64 = 0;
+    for token in tokens {
+        match token.kind {
+            BFToken::IncrementPointer(n) => offset += n as i64,
+            BFToken::DecrementPointer(n) => offset -= n as i64,
+            BFToken::LoopStart | BFToken::LoopEnd => return,
+            _ => {}
+        }
+        if offset < 0 {
+            diagnostics.push(warn(
+                Lint::NegativePointer,
+                "pointer moves left of the first cell".to_string(),
+                token.span,
+                &format!("pointer is at cell {} after this", offset),
+            ));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{BFLexer, Dialect};
+
+    fn lint_names(input: &str, config: &LintConfig) -> Vec<String> {
+        let tokens = BFLexer::new(input.chars())
+            .with_dialect(Dialect::Extended)
+            .tokenize()
+            .unwrap();
+        lint(&tokens, config)
+            .iter()
+            .map(|d| d.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_no_op() {
+        assert_eq!(
+            lint_names("+0>0+", &LintConfig::default()),
+            vec!["`+0` does nothing", "`>0` does nothing"]
+        );
+    }
+
+    #[test]
+    fn test_cancelling() {
+        assert_eq!(
+            lint_names("++ -->><<", &LintConfig::default()),
+            vec![
+                "these `+` and `-` cancel out",
+                "these `>` and `<` cancel out"
+            ]
+        );
+        assert_eq!(
+            lint_names("+200 -100 +156.", &LintConfig::default()),
+            vec!["these `+` and `-` cancel out"]
+        );
+        assert!(lint_names("+++-", &LintConfig::default()).is_empty());
+        let config = LintConfig::default().allow(Lint::NegativePointer);
+        assert!(lint_names(">><<<", &config).is_empty());
+        assert!(lint_names("+256", &LintConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_infinite_loop() {
+        let config = LintConfig::default().allow(Lint::Cancelling);
+        assert_eq!(
+            lint_names("+[]", &config),
+            vec!["this loop never terminates once entered"]
+        );
+        assert_eq!(
+            lint_names("+[+>-<-]", &config),
+            vec!["this loop never terminates once entered"]
+        );
+        assert!(lint_names("+[->+<]", &config).is_empty());
+        assert!(lint_names("+[,]", &config).is_empty());
+        assert!(lint_names("+[>]", &config).is_empty());
+    }
+
+    #[test]
+    fn test_dead_loop() {
+        let tokens = BFLexer::new("+[-]\n[-]".chars()).tokenize().unwrap();
+        let diagnostics = lint(&tokens, &LintConfig::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "this loop is never entered");
+        assert_eq!(diagnostics[0].span.line, 2);
+    }
+
+    #[test]
+    fn test_negative_pointer() {
+        assert_eq!(
+            lint_names(">+<<", &LintConfig::default()),
+            vec!["pointer moves left of the first cell"]
+        );
+        assert!(lint_names("+[<]<", &LintConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_allow() {
+        let config = LintConfig::default().allow(Lint::NoOp);
+        assert!(lint_names("+0", &config).is_empty());
+        assert_eq!(Lint::from_name("dead-loop"), Some(Lint::DeadLoop));
+        assert_eq!(Lint::from_name("nope"), None);
+    }
+}
@@ -1,24 +1,127 @@
-mod asm;
-mod compiler;
-mod format;
-mod lexer;
-
-use clap::Parser;
-use compiler::BFCompiler;
-use format::format_code;
-use lexer::BFLexer;
-use std::process::Command;
+use bf::analysis::{check_pointer_range, pointer_range};
+use bf::compiler::BFCompiler;
+use bf::diff::unified_diff;
+use bf::error::Error;
+use bf::format::{FormatOptions, format_with, split_input, to_standard};
+use bf::ir::to_text;
+use bf::lexer::{BFLexer, Dialect};
+use bf::lint::{Lint, LintConfig, lint};
+use bf::minify::{MinifyOptions, minify};
+use bf::tempdir::TempDir;
+use bf::toolchain::{assemble, check_toolchain, link};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Brainfuck source file
-    filename: String,
+#[command(author, version, about, long_about = None, arg_required_else_help = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Compile a program into an executable; `bf file.b` is short for this
+    Build(BuildArgs),
+    /// Compile a program into a temporary executable and run it
+    Run(RunArgs),
+    /// Format Brainfuck source
+    Fmt(FmtArgs),
+    /// Run lints over the source
+    Check(CheckArgs),
+    /// Print the generated assembly
+    Emit(EmitArgs),
+    /// Compile and run test programs, comparing their output with the expected output
+    Test(TestArgs),
+    /// Run a program with `#` debug dumps and bounds checks enabled
+    Debug(RunArgs),
+}
+
+const COMMANDS: [&str; 8] = [
+    "build", "run", "fmt", "check", "emit", "test", "debug", "help",
+];
 
+/// Options that change how a program is compiled.
+#[derive(Args, Clone)]
+struct CompileOptions {
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
 
+    /// Tape size in bytes
+    #[arg(short = 't', long = "tape-size", default_value_t = 30000)]
+    tape_size: usize,
+
+    /// Target architecture: unix, win64
+    #[arg(short = 'p', long = "platform")]
+    target_arch: Option<String>,
+
+    /// Exit with an error when the pointer leaves the tape
+    #[arg(long = "bounds-check")]
+    bounds_check: bool,
+
+    /// Catch out of range tape access with guard pages (unix only)
+    #[arg(long = "guard-pages")]
+    guard_pages: bool,
+
+    /// Grow the tape when the pointer moves past its right end
+    #[arg(long = "grow-tape", conflicts_with = "guard_pages")]
+    grow_tape: bool,
+
+    /// Wrap the pointer around the ends of the tape
+    #[arg(long = "wrap-pointer", conflicts_with = "grow_tape")]
+    wrap_pointer: bool,
+
+    /// Exit with the value of the current cell
+    #[arg(long = "exit-cell")]
+    exit_cell: bool,
+
+    /// Don't reject programs whose pointer can provably leave the tape
+    #[arg(long = "no-range-check")]
+    no_range_check: bool,
+
+    /// Shrink the tape to the cells the program can reach, when that is known
+    #[arg(long = "shrink-tape")]
+    shrink_tape: bool,
+
+    /// Source dialect: standard, or extended with numeric repeat counts like `+5`
+    #[arg(long = "dialect", default_value = "standard")]
+    dialect: String,
+
+    /// Reject digits after commands instead of reading them as counts
+    #[arg(long = "strict-numbers")]
+    strict_numbers: bool,
+
+    /// Debug build: `#` dumps the pointer and nearby cells to stderr
+    #[arg(long = "debug")]
+    debug: bool,
+
+    /// Everything after the first `!` in the source is the program's input
+    #[arg(long = "input-separator")]
+    input_separator: bool,
+
+    /// Compile while reading the source, with memory use independent of its
+    /// size. Skips the pointer range check
+    #[arg(long = "stream", conflicts_with = "shrink_tape")]
+    stream: bool,
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// Brainfuck source files, or `-` to read stdin. Several files are built
+    /// in parallel
+    #[arg(required = true)]
+    filenames: Vec<String>,
+
+    #[command(flatten)]
+    options: CompileOptions,
+
     /// Don't clean assembly file
     #[arg(short = 'a', long = "keep-asm")]
     keep_asm: bool,
@@ -27,226 +130,893 @@ struct Args {
     #[arg(short = 'A', long = "only-asm")]
     only_asm: bool,
 
-    /// Output executable file name
+    /// Output executable file name (default: the source's name without its
+    /// extension)
     #[arg(short, long, default_value = "")]
     output: String,
 
-    /// Tape size in bytes
-    #[arg(short = 't', long = "tape-size", default_value_t = 30000)]
-    tape_size: usize,
+    /// Directory for the executable and emitted files
+    #[arg(long = "out-dir", value_name = "DIR")]
+    out_dir: Option<String>,
 
-    /// Target architecture: unix, win64
-    #[arg(short = 'p', long = "platform")]
-    target_arch: Option<String>,
+    /// Write these stages next to the output instead of only the executable:
+    /// tokens, ir, optimized-ir, asm, obj, exe
+    #[arg(
+        long = "emit",
+        value_delimiter = ',',
+        value_name = "STAGE",
+        conflicts_with_all = ["keep_asm", "only_asm"]
+    )]
+    emit: Vec<Stage>,
+}
 
-    /// Format Brainfuck source and exit
-    #[arg(long = "format")]
-    format: bool,
+/// A compilation stage that `--emit` can write out.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Stage {
+    Tokens,
+    Ir,
+    OptimizedIr,
+    Asm,
+    Obj,
+    Exe,
 }
 
-fn is_nasm_installed() -> bool {
-    Command::new("nasm")
-        .arg("--version")
-        .output()
-        .map(|output| output.status.success())
-        .unwrap_or(false)
+#[derive(Args)]
+struct RunArgs {
+    /// Brainfuck source file, or `-` to read stdin
+    filename: String,
+
+    #[command(flatten)]
+    options: CompileOptions,
 }
 
-fn is_ld_installed() -> bool {
-    Command::new("ld")
-        .arg("--version")
-        .output()
-        .map(|output| output.status.success())
-        .unwrap_or(false)
+#[derive(Args)]
+struct EmitArgs {
+    /// Brainfuck source file, or `-` to read stdin
+    filename: String,
+
+    #[command(flatten)]
+    options: CompileOptions,
+
+    /// Write the assembly to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
 }
 
-fn main() {
-    let args = Args::parse();
+#[derive(Args)]
+struct TestArgs {
+    /// Test directories holding `prog.b`, `expected.out` and optionally
+    /// `input.in`, or directories to search for them
+    #[arg(default_value = "tests")]
+    paths: Vec<String>,
 
-    if args.format {
-        let source = match std::fs::read_to_string(&args.filename) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to read source file: {}", e);
-                std::process::exit(1);
-            }
-        };
-        let formatted = format_code(&source);
+    #[command(flatten)]
+    options: CompileOptions,
+}
 
-        // write to file
-        std::fs::write(&args.filename, formatted).expect("Failed to write formatted code to file");
-        if args.verbose {
-            println!("Formatted code written to {}", args.filename);
+#[derive(Args)]
+struct FmtArgs {
+    /// Brainfuck source file, or `-` to read stdin
+    filename: String,
+
+    /// Verbose output
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Source dialect: standard, or extended with numeric repeat counts like `+5`
+    #[arg(long = "dialect", default_value = "standard")]
+    dialect: String,
+
+    /// Print the formatted source (the default)
+    #[arg(long = "stdout", group = "mode")]
+    stdout: bool,
+
+    /// Write the formatted source back into the file
+    #[arg(long = "in-place", group = "mode")]
+    in_place: bool,
+
+    /// Print a diff and fail if the file is not formatted
+    #[arg(long = "check", group = "mode")]
+    check: bool,
+
+    /// Strip all comments and whitespace instead of formatting
+    #[arg(long = "minify")]
+    minify: bool,
+
+    /// With --minify, cancel `+-` and `<>`, reduce cell changes modulo 256
+    /// and drop loops that are never entered
+    #[arg(long = "safe-rewrites", requires = "minify")]
+    safe_rewrites: bool,
+
+    /// With --minify, write runs as `+5` where shorter; the result needs
+    /// --dialect extended
+    #[arg(long = "compress", requires = "minify")]
+    compress: bool,
+
+    /// Rewrite counts like `+5` as plain runs, keeping the layout, so the
+    /// source works with any Brainfuck implementation
+    #[arg(long = "standard", conflicts_with = "minify")]
+    standard: bool,
+
+    /// Everything after the first `!` in the source is the program's input
+    /// and is left as it is
+    #[arg(long = "input-separator")]
+    input_separator: bool,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// Brainfuck source file, or `-` to read stdin
+    filename: String,
+
+    /// Verbose output
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Source dialect: standard, or extended with numeric repeat counts like `+5`
+    #[arg(long = "dialect", default_value = "standard")]
+    dialect: String,
+
+    /// Reject digits after commands instead of reading them as counts
+    #[arg(long = "strict-numbers")]
+    strict_numbers: bool,
+
+    /// Read `#` as the debug dump command
+    #[arg(long = "debug")]
+    debug: bool,
+
+    /// Everything after the first `!` in the source is the program's input
+    #[arg(long = "input-separator")]
+    input_separator: bool,
+
+    /// Disable a lint: no-op, cancelling, infinite-loop, dead-loop, negative-pointer
+    #[arg(long = "allow", value_name = "LINT")]
+    allow: Vec<String>,
+}
+
+impl Commands {
+    // The source file whose diagnostics are reported by `main`
+    fn filename(&self) -> Option<&str> {
+        match self {
+            Commands::Run(args) | Commands::Debug(args) => Some(&args.filename),
+            Commands::Fmt(args) => Some(&args.filename),
+            Commands::Check(args) => Some(&args.filename),
+            Commands::Emit(args) => Some(&args.filename),
+            // These report their own, as there may be several sources
+            Commands::Build(_) | Commands::Test(_) => None,
         }
-        std::process::exit(0);
     }
+}
+
+// `bf file.b ...` is short for `bf build file.b ...`
+fn with_default_command(mut args: Vec<OsString>) -> Vec<OsString> {
+    if let Some(first) = args.get(1).and_then(|arg| arg.to_str())
+        && !COMMANDS.contains(&first)
+        && !matches!(first, "-h" | "--help" | "-V" | "--version")
+    {
+        args.insert(1, "build".into());
+    }
+    args
+}
 
-    // Detect NASM and ld at start, but only if compilation will be needed
-    if !args.only_asm {
-        if !is_nasm_installed() {
-            eprintln!("Error: NASM is not installed or not found in PATH.");
+fn main() {
+    let cli = Cli::parse_from(with_default_command(std::env::args_os().collect()));
+    match run(&cli.command) {
+        Ok(0) => {}
+        Ok(status) => std::process::exit(status),
+        Err(Error::Syntax(diagnostics)) => {
+            let filename = cli.command.filename().unwrap_or_default();
+            report(&diagnostics, filename);
             std::process::exit(1);
         }
-        if !is_ld_installed() {
-            eprintln!("Error: ld is not installed or not found in PATH.");
+        Err(e) => {
+            eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     }
+}
 
-    // Auto-detect OS if target_arch not specified
-    let detected_arch = if let Some(ref arch) = args.target_arch {
+fn report(diagnostics: &[bf::diagnostic::Diagnostic], filename: &str) {
+    // The source was readable, or there would be no diagnostics
+    let source = read_source(filename).unwrap_or_default();
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic.render(&source, display_name(filename)));
+    }
+}
+
+fn dialect(name: &str) -> Result<Dialect, Error> {
+    Dialect::from_name(name)
+        .ok_or_else(|| Error::InvalidOptions(format!("Unknown dialect: {}", name)))
+}
+
+// Stdin can only be read once, so its text is kept for diagnostics
+static STDIN: OnceLock<String> = OnceLock::new();
+
+// Reads the source file, or stdin for `-`
+fn read_source(filename: &str) -> Result<String, Error> {
+    if filename != "-" {
+        return std::fs::read_to_string(filename).map_err(|e| Error::io(filename, e));
+    }
+    if let Some(source) = STDIN.get() {
+        return Ok(source.clone());
+    }
+    let mut source = String::new();
+    std::io::stdin()
+        .read_to_string(&mut source)
+        .map_err(|e| Error::io("<stdin>", e))?;
+    Ok(STDIN.get_or_init(|| source).clone())
+}
+
+// How the source is named in messages
+fn display_name(filename: &str) -> &str {
+    if filename == "-" { "<stdin>" } else { filename }
+}
+
+// Auto-detect OS if no target is given
+fn target_arch(options: &CompileOptions) -> String {
+    if let Some(ref arch) = options.target_arch {
         arch.clone()
+    } else if cfg!(windows) {
+        "win64".to_string()
     } else {
-        if cfg!(windows) {
-            "win64".to_string()
+        "unix".to_string()
+    }
+}
+
+// Formatter style from the nearest `.bffmt`, or the `[format]` table of the
+// nearest `bf.toml`, looking upwards from the source file's directory
+fn format_options(source: &Path) -> Result<FormatOptions, Error> {
+    let start = source
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("."));
+    for dir in start.ancestors() {
+        for (name, table) in [(".bffmt", None), ("bf.toml", Some("format"))] {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).map_err(|e| Error::io(&path, e))?;
+            return FormatOptions::parse_config(&text, table)
+                .map_err(|msg| Error::InvalidOptions(format!("{}: {}", path.display(), msg)));
+        }
+    }
+    Ok(FormatOptions::default())
+}
+
+// Runs a command and returns the exit status for the process
+fn run(command: &Commands) -> Result<i32, Error> {
+    match command {
+        Commands::Build(args) => build(args),
+        Commands::Run(args) => run_program(&args.filename, &args.options),
+        Commands::Debug(args) => {
+            let options = CompileOptions {
+                debug: true,
+                bounds_check: true,
+                ..args.options.clone()
+            };
+            run_program(&args.filename, &options)
+        }
+        Commands::Fmt(args) => fmt(args).map(|()| 0),
+        Commands::Check(args) => check(args).map(|()| 0),
+        Commands::Emit(args) => emit(args).map(|()| 0),
+        Commands::Test(args) => test(args).map(|()| 0),
+    }
+}
+
+fn fmt(args: &FmtArgs) -> Result<(), Error> {
+    if args.in_place && args.filename == "-" {
+        return Err(Error::InvalidOptions(
+            "--in-place needs a source file, not stdin".to_string(),
+        ));
+    }
+    let source = read_source(&args.filename)?;
+    let name = display_name(&args.filename);
+    let formatted = if args.minify || args.standard {
+        // Embedded input is data, copied as is after the rewritten program
+        let (code, input) = if args.input_separator {
+            split_input(&source)
+        } else {
+            (source.as_str(), None)
+        };
+        let mut rewritten = if args.minify {
+            let tokens = BFLexer::new(code.chars())
+                .with_dialect(dialect(&args.dialect)?)
+                .tokenize()?;
+            BFLexer::<std::str::Chars>::check_syntax(&tokens)?;
+            let options = MinifyOptions {
+                rewrite: args.safe_rewrites,
+                compress: args.compress,
+            };
+            minify(&tokens, &options)?
         } else {
-            "unix".to_string()
+            to_standard(code)?
+        };
+        if let Some(input) = input {
+            rewritten.push('!');
+            rewritten.push_str(input);
+        }
+        rewritten
+    } else {
+        let mut options = format_options(Path::new(&args.filename))?;
+        options.input_separator = args.input_separator;
+        let mut formatted = format_with(&source, &options, dialect(&args.dialect)?)?;
+        // Embedded input keeps its own line ending
+        let has_input = args.input_separator && split_input(&source).1.is_some();
+        if !formatted.is_empty() && !has_input {
+            formatted.push('\n');
         }
+        formatted
     };
-    let target_arch = detected_arch;
+    if args.check {
+        if formatted != source {
+            let diff = unified_diff(&source, &formatted, name, &format!("{} (formatted)", name));
+            print!("{}", diff);
+            return Err(Error::Unformatted(name.to_string()));
+        }
+        if args.verbose {
+            println!("{} is formatted", name);
+        }
+    } else if args.in_place {
+        // Nothing to lose by skipping the write, and the file keeps its
+        // timestamp
+        if formatted != source {
+            std::fs::write(&args.filename, formatted).map_err(|e| Error::io(&args.filename, e))?;
+        }
+        if args.verbose {
+            println!("Formatted code written to {}", args.filename);
+        }
+    } else {
+        print!("{}", formatted);
+    }
+    Ok(())
+}
 
-    let source = match std::fs::read_to_string(&args.filename) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Failed to read source file: {}", e);
-            std::process::exit(1);
+fn check(args: &CheckArgs) -> Result<(), Error> {
+    let source = read_source(&args.filename)?;
+    let mut config = LintConfig::default();
+    for name in &args.allow {
+        let lint = Lint::from_name(name)
+            .ok_or_else(|| Error::InvalidOptions(format!("Unknown lint: {}", name)))?;
+        config = config.allow(lint);
+    }
+    let mut lexer = BFLexer::new(source.chars())
+        .with_dialect(dialect(&args.dialect)?)
+        .with_strict_numbers(args.strict_numbers)
+        .with_debug_dump(args.debug)
+        .with_input_separator(args.input_separator);
+    let tokens = lexer.tokenize()?;
+    BFLexer::<std::str::Chars>::check_syntax(&tokens)?;
+    let mut warnings = lexer.warnings().to_vec();
+    warnings.extend(lint(&tokens, &config));
+    let name = display_name(&args.filename);
+    for warning in &warnings {
+        eprintln!("{}", warning.render(&source, name));
+    }
+    if args.verbose {
+        println!("{} warning(s) in {}", warnings.len(), name);
+    }
+    Ok(())
+}
+
+fn emit(args: &EmitArgs) -> Result<(), Error> {
+    let target_arch = target_arch(&args.options);
+    match &args.output {
+        Some(path) => write_asm(&args.filename, &args.options, &target_arch, Path::new(path))?,
+        None if args.options.stream => {
+            let mut out = std::io::stdout().lock();
+            compile_streaming(
+                &args.filename,
+                &args.options,
+                &target_arch,
+                &mut out,
+                "<stdout>",
+            )?;
         }
+        None => print!("{}", compile(&args.filename, &args.options, &target_arch)?),
+    }
+    Ok(())
+}
+
+// Builds every source, reporting syntax errors against the source they came
+// from, and returns the exit status
+fn build(args: &BuildArgs) -> Result<i32, Error> {
+    let stages = if !args.emit.is_empty() {
+        args.emit.clone()
+    } else if args.only_asm {
+        vec![Stage::Asm]
+    } else if args.keep_asm {
+        vec![Stage::Asm, Stage::Obj, Stage::Exe]
+    } else {
+        vec![Stage::Exe]
     };
+    let options = &args.options;
+    let target_arch = target_arch(options);
+    if args.filenames.len() > 1 && !args.output.is_empty() {
+        return Err(Error::InvalidOptions(
+            "-o can only name the output of a single source file; use --out-dir".to_string(),
+        ));
+    }
+    let mut exes: Vec<PathBuf> = Vec::with_capacity(args.filenames.len());
+    for filename in &args.filenames {
+        let exe = output_path(args, filename, &target_arch)?;
+        if exes.contains(&exe) {
+            return Err(Error::InvalidOptions(format!(
+                "more than one source would be built into {}",
+                exe.display()
+            )));
+        }
+        exes.push(exe);
+    }
+    if options.verbose && args.only_asm {
+        println!("Only assembly output requested (-A). Skipping object and executable generation.");
+    }
+    // Detect NASM and ld once up front, but only if they will be needed
+    if stages.contains(&Stage::Obj) || stages.contains(&Stage::Exe) {
+        check_toolchain()?;
+    }
+    if let [filename] = args.filenames.as_slice() {
+        return match emit_stages(filename, options, &target_arch, &stages, &exes[0]) {
+            Ok(()) => Ok(0),
+            Err(Error::Syntax(diagnostics)) => {
+                report(&diagnostics, filename);
+                Ok(1)
+            }
+            Err(e) => Err(e),
+        };
+    }
 
-    let mut lexer = BFLexer::new(source.chars());
-    let tokens = lexer.tokenize();
-    match BFLexer::<std::str::Chars>::check_syntax(&tokens) {
-        Ok(()) => {}
-        Err(e) => {
-            eprintln!("Syntax error: {}", e);
-            std::process::exit(1);
+    let results = in_parallel(args.filenames.len(), |i| {
+        emit_stages(&args.filenames[i], options, &target_arch, &stages, &exes[i])
+    });
+    let mut failed = 0;
+    for (filename, result) in args.filenames.iter().zip(results) {
+        let name = display_name(filename);
+        match result {
+            Ok(()) => println!("build {} ... ok", name),
+            Err(Error::Syntax(diagnostics)) => {
+                println!("build {} ... FAILED", name);
+                report(&diagnostics, filename);
+                failed += 1;
+            }
+            Err(e) => {
+                println!("build {} ... FAILED", name);
+                eprintln!("Error: {}", e);
+                failed += 1;
+            }
         }
     }
-    let compiler = BFCompiler::new(tokens, args.tape_size, &target_arch, args.verbose);
-    let asm = compiler.compile();
-    let base = if args.output.is_empty() {
-        format!("{}", &args.filename)
-    } else {
-        args.output.clone()
+    println!(
+        "\nbuild result: {} succeeded; {} failed",
+        args.filenames.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        return Err(Error::BuildsFailed(failed));
+    }
+    Ok(0)
+}
+
+// Runs `f` on 0..count with a thread per core, returning the results in order
+fn in_parallel<T: Send>(count: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(count);
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, T)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= count {
+                            break done;
+                        }
+                        done.push((i, f(i)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("build thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// Where the executable for `filename` goes: `-o`, or the source's name without
+// its extension (`a.out` for stdin), in `--out-dir` or the current directory
+fn output_path(args: &BuildArgs, filename: &str, target_arch: &str) -> Result<PathBuf, Error> {
+    let dir = match &args.out_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+            PathBuf::from(dir)
+        }
+        None => PathBuf::new(),
+    };
+    if !args.output.is_empty() {
+        return Ok(dir.join(&args.output));
+    }
+    let stem = match Path::new(filename).file_stem() {
+        Some(stem) if filename != "-" => stem.to_os_string(),
+        _ => "a".into(),
+    };
+    let mut exe = dir.join(stem);
+    if target_arch == "win64" {
+        exe.set_extension("exe");
+    } else if filename == "-" || same_file(&exe, Path::new(filename)) {
+        // Don't let the executable replace a source file without an extension
+        exe.set_extension("out");
+    }
+    Ok(exe)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Writes each of `stages` to a file named after the executable `exe`:
+// `.tokens`, `.ir`, `.opt.ir`, `.asm`, `.o` and the executable itself. Files
+// only needed to reach a requested stage are built in a private temp directory
+// that is removed afterwards, whether the build succeeds or not. The caller
+// checks for NASM and ld, so several builds only do it once.
+fn emit_stages(
+    filename: &str,
+    options: &CompileOptions,
+    target_arch: &str,
+    stages: &[Stage],
+    exe: &Path,
+) -> Result<(), Error> {
+    let emits = |stage| stages.contains(&stage);
+    let needs_obj = emits(Stage::Obj) || emits(Stage::Exe);
+    let needs_asm = needs_obj || emits(Stage::Asm);
+    let needs_tokens = emits(Stage::Tokens) || emits(Stage::Ir) || emits(Stage::OptimizedIr);
+    if needs_tokens && options.stream {
+        return Err(Error::InvalidOptions(
+            "--emit tokens, ir and optimized-ir need the whole program and can't be used with --stream"
+                .to_string(),
+        ));
+    }
+    let temp = TempDir::new()?;
+    // `prog.exe` and `a.out` name their stages `prog.asm` and `a.asm`
+    let base = match exe.extension() {
+        Some(extension) if extension == "exe" || extension == "out" => exe.with_extension(""),
+        _ => exe.to_path_buf(),
+    };
+    let path = |stage, extension: &str| {
+        let name = format!(
+            "{}{}",
+            base.file_name().unwrap_or_default().to_string_lossy(),
+            extension
+        );
+        if emits(stage) {
+            base.with_file_name(name)
+        } else {
+            temp.path().join(name)
+        }
+    };
+    let written = |stage: &str, path: &Path| {
+        if options.verbose {
+            println!("{} written to {}", stage, path.display());
+        }
     };
-    let nfile = format!("{}.temp.asm", base);
-    let output_obj = format!("{}.temp.o", base);
-    let output_exe = if args.output.is_empty() {
-        format!("{}.temp.out", base)
+
+    let compiler = if options.stream {
+        None
     } else {
-        base.clone()
+        Some(compiler(filename, options, target_arch)?)
     };
-    std::fs::write(&nfile, asm).expect("Failed to write assembly file");
-    if args.verbose {
-        println!("Assembly code written to {}", nfile);
-    }
-
-    match target_arch.as_str() {
-        "unix" => {
-            if args.only_asm {
-                if args.verbose {
-                    println!(
-                        "Only assembly output requested (-A). Skipping object and executable generation."
-                    );
-                }
-            } else {
-                if !is_nasm_installed() {
-                    eprintln!("Error: NASM is not installed or not found in PATH.");
-                    std::process::exit(1);
-                }
-                if !is_ld_installed() {
-                    eprintln!("Error: ld is not installed or not found in PATH.");
-                    std::process::exit(1);
-                }
-                let nasm_status = Command::new("nasm")
-                    .args(&["-f", "elf64", &nfile, "-o", &output_obj])
-                    .status()
-                    .expect("Failed to execute NASM");
-                if !nasm_status.success() {
-                    eprintln!("Error: NASM failed to assemble the code.");
-                    std::process::exit(1);
-                }
-                if args.verbose {
-                    println!("Object file written to {}", output_obj);
-                }
-
-                let ld_status = Command::new("ld")
-                    .args(&[&output_obj, "-o", &output_exe])
-                    .status()
-                    .expect("Failed to execute ld");
-                if !ld_status.success() {
-                    eprintln!("Error: ld failed to link the object file.");
-                    std::process::exit(1);
-                }
-                if args.verbose {
-                    println!("Executable file written to {}", output_exe);
-                }
+    if let Some(compiler) = &compiler {
+        let tokens: String = compiler
+            .tokens
+            .iter()
+            .map(|t| format!("{}:{}\t{:?}\n", t.span.line, t.span.column, t.kind))
+            .collect();
+        let texts = [
+            (Stage::Tokens, "Tokens", ".tokens", tokens),
+            (Stage::Ir, "IR", ".ir", to_text(&compiler.ir())),
+            (
+                Stage::OptimizedIr,
+                "Optimized IR",
+                ".opt.ir",
+                to_text(&compiler.optimized_ir()),
+            ),
+        ];
+        for (stage, name, extension, text) in texts {
+            if emits(stage) {
+                let file = path(stage, extension);
+                std::fs::write(&file, text).map_err(|e| Error::io(&file, e))?;
+                written(name, &file);
             }
         }
-        "win64" => {
-            if args.only_asm {
-                if args.verbose {
-                    println!(
-                        "Only assembly output requested (-A). Skipping object and executable generation."
-                    );
-                }
-            } else {
-                if !is_nasm_installed() {
-                    eprintln!("Error: NASM is not installed or not found in PATH.");
-                    std::process::exit(1);
-                }
-                if !is_ld_installed() {
-                    eprintln!("Error: ld is not installed or not found in PATH.");
-                    std::process::exit(1);
-                }
-                let nasm_status = Command::new("nasm")
-                    .args(&["-f", "win64", &nfile, "-o", &output_obj])
-                    .status()
-                    .expect("Failed to execute NASM");
-                if !nasm_status.success() {
-                    eprintln!("Error: NASM failed to assemble the code for win64.");
-                    std::process::exit(1);
-                }
-                if args.verbose {
-                    println!("Object file written to {}", output_obj);
-                }
-
-                // Link with ld for win64
-                let ld_status = Command::new("ld")
-                    .args(&[
-                        &output_obj,
-                        "-o",
-                        &output_exe,
-                        "-e",
-                        "main",
-                        "-subsystem",
-                        "console",
-                        "-lmsvcrt",
-                    ])
-                    .status()
-                    .expect("Failed to execute ld");
-                if !ld_status.success() {
-                    eprintln!(
-                        "Error: ld failed to link the object file for win64. Ensure ld is installed and in PATH."
-                    );
-                    std::process::exit(1);
-                }
-                if args.verbose {
-                    println!("Executable file written to {} (ld)", output_exe);
-                }
+    }
+    if !needs_asm {
+        return Ok(());
+    }
+
+    let asm = path(Stage::Asm, ".asm");
+    match &compiler {
+        Some(compiler) => {
+            std::fs::write(&asm, compiler.compile()?).map_err(|e| Error::io(&asm, e))?
+        }
+        None => write_asm(filename, options, target_arch, &asm)?,
+    }
+    written("Assembly code", &asm);
+    if !needs_obj {
+        return Ok(());
+    }
+    let obj = path(Stage::Obj, ".o");
+    assemble(&asm, &obj, target_arch)?;
+    written("Object file", &obj);
+    if emits(Stage::Exe) {
+        link(&obj, exe, target_arch)?;
+        written("Executable file", exe);
+    }
+    Ok(())
+}
+
+// Builds `filename` into an executable in a private temp directory and runs
+// `f` on its path. The directory is removed again afterwards. NASM and ld
+// must already have been checked for.
+fn with_temp_build<T>(
+    filename: &str,
+    options: &CompileOptions,
+    f: impl FnOnce(&Path) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let target_arch = target_arch(options);
+    let temp = TempDir::new()?;
+    let exe = temp.path().join(if target_arch == "win64" {
+        "prog.exe"
+    } else {
+        "prog"
+    });
+    emit_stages(filename, options, &target_arch, &[Stage::Exe], &exe)?;
+    f(&exe)
+}
+
+// Builds and runs a program with the terminal's stdin and stdout, returning
+// its exit status
+fn run_program(filename: &str, options: &CompileOptions) -> Result<i32, Error> {
+    check_toolchain()?;
+    with_temp_build(filename, options, |exe| {
+        let status = Command::new(exe).status().map_err(|e| Error::io(exe, e))?;
+        Ok(status.code().unwrap_or(1))
+    })
+}
+
+fn test(args: &TestArgs) -> Result<(), Error> {
+    check_toolchain()?;
+    let mut cases = Vec::new();
+    for path in &args.paths {
+        find_test_cases(Path::new(path), &mut cases)?;
+    }
+    let mut failed = 0;
+    for case in &cases {
+        let program = case.join("prog.b");
+        let filename = program.display().to_string();
+        let result = with_temp_build(&filename, &args.options, |exe| run_test_case(case, exe));
+        let name = case.display();
+        match result {
+            Ok(None) => println!("test {} ... ok", name),
+            Ok(Some(diff)) => {
+                println!("test {} ... FAILED", name);
+                print!("{}", diff);
+                failed += 1;
+            }
+            Err(Error::Syntax(diagnostics)) => {
+                println!("test {} ... FAILED", name);
+                report(&diagnostics, &filename);
+                failed += 1;
+            }
+            Err(e) => {
+                println!("test {} ... FAILED", name);
+                eprintln!("Error: {}", e);
+                failed += 1;
             }
         }
-        _ => {
-            eprintln!("Unknown target architecture: {:?}", args.target_arch);
-            std::process::exit(1);
+    }
+    println!(
+        "\ntest result: {} passed; {} failed",
+        cases.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        return Err(Error::TestsFailed(failed));
+    }
+    Ok(())
+}
+
+// Collects `dir` if it holds a `prog.b`, otherwise searches its subdirectories
+fn find_test_cases(dir: &Path, cases: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if dir.join("prog.b").is_file() {
+        cases.push(dir.to_path_buf());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| Error::io(dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        find_test_cases(&entry, cases)?;
+    }
+    Ok(())
+}
+
+// Runs `exe` with the case's input and returns a diff of its output against
+// the expected output, or None if they match
+fn run_test_case(case: &Path, exe: &Path) -> Result<Option<String>, Error> {
+    let input_path = case.join("input.in");
+    let input = if input_path.exists() {
+        std::fs::read(&input_path).map_err(|e| Error::io(&input_path, e))?
+    } else {
+        Vec::new()
+    };
+    let expected_path = case.join("expected.out");
+    let expected = std::fs::read(&expected_path).map_err(|e| Error::io(&expected_path, e))?;
+    let mut child = Command::new(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::io(exe, e))?;
+    // Written from another thread so a program that prints before reading
+    // everything can't deadlock on a full pipe
+    let mut stdin = child.stdin.take();
+    let writer = std::thread::spawn(move || {
+        if let Some(stdin) = stdin.as_mut() {
+            let _ = stdin.write_all(&input);
         }
+    });
+    let output = child.wait_with_output().map_err(|e| Error::io(exe, e))?;
+    let _ = writer.join();
+    if output.stdout == expected {
+        return Ok(None);
     }
+    Ok(Some(unified_diff(
+        &String::from_utf8_lossy(&expected),
+        &String::from_utf8_lossy(&output.stdout),
+        &expected_path.display().to_string(),
+        "output",
+    )))
+}
 
-    if !args.keep_asm && !args.only_asm {
-        std::fs::remove_file(&nfile).expect("Failed to remove temporary assembly file");
-        std::fs::remove_file(&output_obj).expect("Failed to remove temporary object file");
-        if args.verbose {
-            println!("Temporary files removed.");
+// Compiles `filename` into the assembly file `asm_path`
+fn write_asm(
+    filename: &str,
+    options: &CompileOptions,
+    target_arch: &str,
+    asm_path: &Path,
+) -> Result<(), Error> {
+    if options.stream {
+        let file = File::create(asm_path).map_err(|e| Error::io(asm_path, e))?;
+        let mut out = BufWriter::new(file);
+        let name = asm_path.display().to_string();
+        let result = compile_streaming(filename, options, target_arch, &mut out, &name)
+            .and_then(|()| out.flush().map_err(|e| Error::io(asm_path, e)));
+        drop(out);
+        if result.is_err() {
+            let _ = std::fs::remove_file(asm_path);
+        }
+        result
+    } else {
+        let asm = compile(filename, options, target_arch)?;
+        std::fs::write(asm_path, asm).map_err(|e| Error::io(asm_path, e))
+    }
+}
+
+// Reads the whole source and compiles it, with the checks that need the
+// entire program
+fn compile(filename: &str, options: &CompileOptions, target_arch: &str) -> Result<String, Error> {
+    compiler(filename, options, target_arch)?.compile()
+}
+
+// Lexes and checks the whole source, and sets up the compiler for it
+fn compiler(
+    filename: &str,
+    options: &CompileOptions,
+    target_arch: &str,
+) -> Result<BFCompiler, Error> {
+    let source = read_source(filename)?;
+
+    let mut lexer = BFLexer::new(source.chars())
+        .with_dialect(dialect(&options.dialect)?)
+        .with_strict_numbers(options.strict_numbers)
+        .with_debug_dump(options.debug)
+        .with_input_separator(options.input_separator);
+    let tokens = lexer.tokenize()?;
+    for warning in lexer.warnings() {
+        eprintln!("{}", warning.render(&source, display_name(filename)));
+    }
+    BFLexer::<std::str::Chars>::check_syntax(&tokens)?;
+
+    // A wrapping pointer can't leave the tape, and shrinking it would change
+    // where it wraps
+    let mut tape_size = options.tape_size;
+    if !options.wrap_pointer {
+        let right_end = if options.grow_tape {
+            None
+        } else {
+            Some(tape_size)
+        };
+        let range = if options.no_range_check {
+            pointer_range(&tokens)
+        } else {
+            check_pointer_range(&tokens, right_end)?
+        };
+        if options.shrink_tape
+            && let Some(range) = range
+            && range.min >= 0
+        {
+            tape_size = range.max as usize + 1;
+            if options.verbose {
+                println!("Tape shrunk to {} cells", tape_size);
+            }
+        }
+    }
+
+    Ok(
+        BFCompiler::new(tokens, tape_size, target_arch, options.verbose)
+            .with_bounds_check(options.bounds_check)
+            .with_guard_pages(options.guard_pages)
+            .with_grow_tape(options.grow_tape)
+            .with_wrap_pointer(options.wrap_pointer)
+            .with_exit_cell(options.exit_cell)
+            .with_input(
+                lexer
+                    .embedded_input()
+                    .map(|input| input.as_bytes().to_vec()),
+            ),
+    )
+}
+
+// Lexes and compiles straight from the source file into `out`, without
+// holding either in memory
+fn compile_streaming<W: Write>(
+    filename: &str,
+    options: &CompileOptions,
+    target_arch: &str,
+    out: &mut W,
+    out_name: &str,
+) -> Result<(), Error> {
+    let source: Box<dyn Read> = if filename == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(File::open(filename).map_err(|e| Error::io(filename, e))?)
+    };
+    let mut lexer = BFLexer::from_reader(source)
+        .with_dialect(dialect(&options.dialect)?)
+        .with_strict_numbers(options.strict_numbers)
+        .with_debug_dump(options.debug)
+        .with_input_separator(options.input_separator);
+    let compiler = BFCompiler::streaming(options.tape_size, target_arch, options.verbose)
+        .with_bounds_check(options.bounds_check)
+        .with_guard_pages(options.guard_pages)
+        .with_grow_tape(options.grow_tape)
+        .with_wrap_pointer(options.wrap_pointer)
+        .with_exit_cell(options.exit_cell);
+    let mut result = compiler.compile_stream(&mut lexer, out, Path::new(out_name));
+    if let Some(e) = lexer.take_read_error() {
+        result = Err(Error::io(display_name(filename), e));
+    }
+    // Stdin is gone, so its diagnostics come without source lines instead of
+    // `read_source` waiting for more of it
+    if filename == "-" {
+        STDIN.get_or_init(String::new);
+    }
+    // Only read the source back when there is something to point into
+    if !lexer.warnings().is_empty() {
+        let source = read_source(filename).unwrap_or_default();
+        for warning in lexer.warnings() {
+            eprintln!("{}", warning.render(&source, display_name(filename)));
         }
     }
+    result
 }
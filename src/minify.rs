@@ -0,0 +1,254 @@
+use crate::error::Error;
+use crate::format::MAX_EXPANDED_RUN;
+use crate::lexer::{BFToken, Token};
+
+/// What `minify` may change besides removing comments and whitespace.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct MinifyOptions {
+    /// Cancel `+-` and `<>` pairs, reduce cell changes modulo 256 and drop
+    /// loops that can never be entered
+    pub rewrite: bool,
+    /// Write runs as `+5` where that is shorter. The output then needs the
+    /// extended dialect.
+    pub compress: bool,
+}
+
+// Cells wrap, so a change of 200 is the shorter `-56`
+fn wrap(n: i64) -> i64 {
+    (n + 127).rem_euclid(256) - 127
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Op {
+    /// Net cell change, or run of `+`/`-` when not rewriting
+    Value(i64),
+    /// Net pointer move, or run of `>`/`<` when not rewriting
+    Pointer(i64),
+    Output(u64),
+    Input(u64),
+    LoopStart,
+    LoopEnd,
+    DebugDump,
+}
+
+impl Op {
+    fn from_token(kind: BFToken, rewrite: bool) -> Op {
+        match kind {
+            BFToken::IncrementValue(n) if rewrite => Op::Value(wrap(n as i64)),
+            BFToken::DecrementValue(n) if rewrite => Op::Value(wrap(-(n as i64))),
+            BFToken::IncrementPointer(n) => Op::Pointer(n as i64),
+            BFToken::DecrementPointer(n) => Op::Pointer(-(n as i64)),
+            BFToken::IncrementValue(n) => Op::Value(n as i64),
+            BFToken::DecrementValue(n) => Op::Value(-(n as i64)),
+            BFToken::OutputValue(n) => Op::Output(n as u64),
+            BFToken::InputValue(n) => Op::Input(n as u64),
+            BFToken::LoopStart => Op::LoopStart,
+            BFToken::LoopEnd => Op::LoopEnd,
+            BFToken::DebugDump => Op::DebugDump,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(
+            self,
+            Op::Value(0) | Op::Pointer(0) | Op::Output(0) | Op::Input(0)
+        )
+    }
+
+    fn changes_cells(&self) -> bool {
+        matches!(self, Op::Value(_) | Op::Input(_))
+    }
+
+    // `self` followed by `next` as a single op. Without rewrites only runs in
+    // the same direction are joined.
+    fn join(&self, next: &Op, rewrite: bool) -> Option<Op> {
+        let same_sign = |a: i64, b: i64| rewrite || (a < 0) == (b < 0);
+        match (*self, *next) {
+            (Op::Value(a), Op::Value(b)) if same_sign(a, b) => {
+                let n = a + b;
+                Some(Op::Value(if rewrite { wrap(n) } else { n }))
+            }
+            (Op::Pointer(a), Op::Pointer(b)) if same_sign(a, b) => Some(Op::Pointer(a + b)),
+            (Op::Output(a), Op::Output(b)) => Some(Op::Output(a + b)),
+            (Op::Input(a), Op::Input(b)) => Some(Op::Input(a + b)),
+            _ => None,
+        }
+    }
+
+    fn render(&self, compress: bool, out: &mut String) -> Result<(), Error> {
+        let (c, mut n) = match *self {
+            Op::Value(n) if n < 0 => ('-', n.unsigned_abs()),
+            Op::Value(n) => ('+', n as u64),
+            Op::Pointer(n) if n < 0 => ('<', n.unsigned_abs()),
+            Op::Pointer(n) => ('>', n as u64),
+            Op::Output(n) => ('.', n),
+            Op::Input(n) => (',', n),
+            Op::LoopStart => ('[', 1),
+            Op::LoopEnd => (']', 1),
+            Op::DebugDump => ('#', 1),
+        };
+        if !compress {
+            match c {
+                // Cells wrap, so only the count modulo 256 matters
+                '+' | '-' => n %= 256,
+                _ if n > MAX_EXPANDED_RUN => {
+                    return Err(Error::InvalidOptions(format!(
+                        "a run of {} `{}` is too long to write out command by command; use --compress",
+                        n, c
+                    )));
+                }
+                _ => {}
+            }
+        }
+        let mut left = n;
+        while left > 0 {
+            let chunk = left.min(u32::MAX as u64);
+            let count = chunk.to_string();
+            if compress && count.len() + 1 < chunk as usize {
+                out.push(c);
+                out.push_str(&count);
+            } else {
+                out.extend(std::iter::repeat_n(c, chunk as usize));
+            }
+            left -= chunk;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the program as the shortest text `options` allow: commands only,
+/// with no comments or whitespace. `tokens` must have balanced brackets.
+/// Without `compress`, `+` and `-` runs are reduced modulo 256, and other runs
+/// longer than `MAX_EXPANDED_RUN` are errors.
+pub fn minify(tokens: &[Token], options: &MinifyOptions) -> Result<String, Error> {
+    let mut ops: Vec<Op> = Vec::new();
+    // Ops in `ops` that change cells. Without any, every cell is still zero.
+    let mut changes = 0;
+    let mut tokens = tokens.iter();
+    while let Some(token) = tokens.next() {
+        let op = Op::from_token(token.kind, options.rewrite);
+        if op == Op::LoopStart
+            && options.rewrite
+            && (changes == 0 || ops.last() == Some(&Op::LoopEnd))
+        {
+            // The current cell is zero, so the loop is skipped
+            let mut depth = 1;
+            for token in tokens.by_ref() {
+                match token.kind {
+                    BFToken::LoopStart => depth += 1,
+                    BFToken::LoopEnd => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    break;
+                }
+            }
+            continue;
+        }
+        if op.is_empty() {
+            continue;
+        }
+        if let Some(last) = ops.last_mut()
+            && let Some(joined) = last.join(&op, options.rewrite)
+        {
+            if joined.is_empty() {
+                if last.changes_cells() {
+                    changes -= 1;
+                }
+                ops.pop();
+            } else {
+                *last = joined;
+            }
+            continue;
+        }
+        if op.changes_cells() {
+            changes += 1;
+        }
+        ops.push(op);
+    }
+    let mut out = String::new();
+    for op in &ops {
+        op.render(options.compress, &mut out)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{BFLexer, Dialect};
+
+    fn minified(source: &str, rewrite: bool, compress: bool) -> String {
+        let tokens = BFLexer::new(source.chars())
+            .with_dialect(Dialect::Extended)
+            .tokenize()
+            .unwrap();
+        minify(&tokens, &MinifyOptions { rewrite, compress }).unwrap()
+    }
+
+    #[test]
+    fn test_strips_comments() {
+        assert_eq!(
+            minified("set x\n  ++ +\n[ loop\n\t->+< ]\n", false, false),
+            "+++[->+<]"
+        );
+        assert_eq!(minified("+3 +2 >0 +- <>", false, false), "++++++-<>");
+    }
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(minified("+-", true, false), "");
+        assert_eq!(minified(">+-<.", true, false), ".");
+        assert_eq!(minified("++>--<<>+260", true, false), "++>--<++++");
+        assert_eq!(minified("+250.", true, false), "------.");
+        assert_eq!(minified("+250.", false, true), "+250.");
+        // Loops on a cell that is known to be zero
+        assert_eq!(minified("[-]>>[.]+[-][+++.]-", true, false), ">>+[-]-");
+        assert_eq!(minified("+-[.]", true, false), "");
+        // Input makes the cell unknown
+        assert_eq!(minified(",[.,]", true, false), ",[.,]");
+    }
+
+    #[test]
+    fn test_compress() {
+        assert_eq!(minified("++>>>.........", false, true), "++>3.9");
+        assert_eq!(minified("+10-2>>", true, true), "+8>>");
+        assert_eq!(minified("<5>5", false, true), "<5>5");
+        assert_eq!(minified("<5>5", true, true), "");
+    }
+
+    #[test]
+    fn test_long_runs() {
+        assert_eq!(
+            minified("+4000000001 -300", false, false),
+            "+--------------------------------------------"
+        );
+        assert_eq!(minified("+4000000001", false, true), "+4000000001");
+        assert_eq!(minified(">4000000000", false, true), ">4000000000");
+        let tokens = BFLexer::new(">4000000000".chars())
+            .with_dialect(Dialect::Extended)
+            .tokenize()
+            .unwrap();
+        assert!(matches!(
+            minify(&tokens, &MinifyOptions::default()),
+            Err(Error::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_test_programs_keep_tokens() {
+        for src in [
+            include_str!("../tests/collatz/prog.b"),
+            include_str!("../tests/numwarp/prog.b"),
+            include_str!("../tests/rot13/prog.b"),
+        ] {
+            let tokens = BFLexer::new(src.chars()).tokenize().unwrap();
+            let minified = minify(&tokens, &MinifyOptions::default()).unwrap();
+            let kinds = |tokens: &[Token]| tokens.iter().map(|t| t.kind).collect::<Vec<_>>();
+            assert_eq!(
+                kinds(&BFLexer::new(minified.chars()).tokenize().unwrap()),
+                kinds(&tokens)
+            );
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory in the system temp directory, only accessible to the
+/// current user on unix. It is removed with everything in it when dropped,
+/// whether the build succeeded or not.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<TempDir, Error> {
+        let base = std::env::temp_dir();
+        loop {
+            let path = base.join(format!(
+                "bf-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut builder = std::fs::DirBuilder::new();
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                // Left behind by an earlier process with the same id
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::io(path, e)),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_removed_on_drop() {
+        let dir = TempDir::new().unwrap();
+        let other = TempDir::new().unwrap();
+        assert_ne!(dir.path(), other.path());
+        std::fs::write(dir.path().join("prog.asm"), "").unwrap();
+        let path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(!path.exists());
+        assert!(other.path().is_dir());
+    }
+}
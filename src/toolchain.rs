@@ -0,0 +1,120 @@
+use crate::error::Error;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn is_installed(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Checks that NASM and ld are available.
+pub fn check_toolchain() -> Result<(), Error> {
+    if !is_installed("nasm") {
+        return Err(Error::ToolchainMissing("NASM".to_string()));
+    }
+    if !is_installed("ld") {
+        return Err(Error::ToolchainMissing("ld".to_string()));
+    }
+    Ok(())
+}
+
+// Runs `command`, capturing its output so it only reaches the user through
+// the returned error. Only a missing program counts as a missing toolchain.
+fn run(command: &mut Command, tool: &str) -> Result<Output, Error> {
+    command.output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::ToolchainMissing(tool.to_string()),
+        _ => Error::io(tool, e),
+    })
+}
+
+// What a failed tool printed, stderr first
+fn diagnostics(output: &Output) -> String {
+    let mut text = String::from_utf8_lossy(&output.stderr).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stdout));
+    text.trim_end().to_string()
+}
+
+/// Assembles `asm` into the object file `obj` with NASM.
+pub fn assemble(asm: &Path, obj: &Path, target_arch: &str) -> Result<(), Error> {
+    let format = match target_arch {
+        "unix" => "elf64",
+        "win64" => "win64",
+        _ => return Err(Error::UnsupportedTarget(target_arch.to_string())),
+    };
+    let output = run(
+        Command::new("nasm")
+            .arg("-f")
+            .arg(format)
+            .arg(asm)
+            .arg("-o")
+            .arg(obj),
+        "NASM",
+    )?;
+    if !output.status.success() {
+        return Err(Error::AssemblerFailed {
+            target: target_arch.to_string(),
+            stderr: diagnostics(&output),
+        });
+    }
+    Ok(())
+}
+
+/// Links the object file `obj` into the executable `exe` with ld.
+pub fn link(obj: &Path, exe: &Path, target_arch: &str) -> Result<(), Error> {
+    let mut command = Command::new("ld");
+    command.arg(obj).arg("-o").arg(exe);
+    match target_arch {
+        "unix" => {}
+        "win64" => {
+            command.args([
+                "-e",
+                "main",
+                "-subsystem",
+                "console",
+                "-lmsvcrt",
+                "-lkernel32",
+            ]);
+        }
+        _ => return Err(Error::UnsupportedTarget(target_arch.to_string())),
+    }
+    let output = run(&mut command, "ld")?;
+    if !output.status.success() {
+        return Err(Error::LinkerFailed {
+            target: target_arch.to_string(),
+            stderr: diagnostics(&output),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_failure_keeps_output() {
+        if !is_installed("ld") {
+            return;
+        }
+        let missing = std::env::temp_dir().join("bf-missing-object.o");
+        match link(&missing, &missing.with_extension("out"), "unix") {
+            Err(Error::LinkerFailed { stderr, .. }) => {
+                assert!(stderr.contains("bf-missing-object.o"), "{}", stderr)
+            }
+            other => panic!("expected a linker failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_spawn_errors() {
+        let missing = run(&mut Command::new("bf-no-such-tool"), "tool");
+        assert!(matches!(missing, Err(Error::ToolchainMissing(tool)) if tool == "tool"));
+        // A directory is found but can't be run
+        let dir = std::env::temp_dir();
+        let denied = run(&mut Command::new(&dir), "tool");
+        assert!(matches!(denied, Err(Error::Io { .. })), "{:?}", denied);
+    }
+}
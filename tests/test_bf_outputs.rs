@@ -1,4 +1,5 @@
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
@@ -34,8 +35,8 @@ fn test_bf_compiler_outputs() {
             let in_file = case_dir.join("input.in");
             let out_file = case_dir.join("expected.out");
             let file_stem = case_dir.file_name().unwrap().to_string_lossy();
-            let asm_file = format!("test_out/{}.temp.asm", file_stem);
-            let obj_file = format!("test_out/{}.temp.o", file_stem);
+            let asm_file = format!("test_out/{}.asm", file_stem);
+            let obj_file = format!("test_out/{}.o", file_stem);
             let exe_file = format!("test_out/{}", file_stem);
             // Compile
             let status = Command::new("cargo")
@@ -49,6 +50,8 @@ fn test_bf_compiler_outputs() {
                     "-p",
                     target_arch,
                     "-a",
+                    "--dialect",
+                    "extended",
                 ])
                 .status()
                 .expect("Failed to run compiler");
@@ -128,3 +131,54 @@ fn test_bf_compiler_outputs() {
         }
     }
 }
+
+/// Runs the same test cases through `bf test`.
+#[test]
+fn test_test_command() {
+    let status = Command::new("cargo")
+        .args([
+            "run",
+            "--release",
+            "--",
+            "test",
+            "tests",
+            "--dialect",
+            "extended",
+        ])
+        .status()
+        .expect("Failed to run compiler");
+    assert!(status.success(), "bf test failed");
+}
+
+/// Builds several programs in one invocation. Each gets its own executable,
+/// and a failing source doesn't stop the others.
+#[test]
+fn test_build_several() {
+    let out_dir = Path::new("test_out/several");
+    if out_dir.exists() {
+        fs::remove_dir_all(out_dir).expect("Failed to clean test_out/several");
+    }
+    let build = |sources: &[&str]| {
+        let mut child = Command::new("cargo")
+            .args(["run", "--release", "--", "build"])
+            .args(sources)
+            .args(["--out-dir", "test_out/several", "--dialect", "extended"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Failed to run compiler");
+        let source = fs::read("tests/collatz/prog.b").expect("Failed to read source");
+        // Builds that fail early may never read it
+        let _ = child.stdin.take().unwrap().write_all(&source);
+        child.wait().expect("Failed to run compiler")
+    };
+    // Both sources are named prog.b, so they would overwrite each other
+    let status = build(&["tests/collatz/prog.b", "tests/rot13/prog.b"]);
+    assert!(!status.success(), "Clashing outputs were accepted");
+    let status = build(&["-", "tests/rot13/prog.b", "tests/nonexistent.b"]);
+    assert!(!status.success(), "A missing source was not reported");
+    assert!(out_dir.join("a.out").is_file());
+    assert!(out_dir.join("prog").is_file());
+    // Clean up after
+    fs::remove_dir_all(out_dir).expect("Failed to clean test_out/several");
+}